@@ -0,0 +1,214 @@
+use anyhow::{Error, anyhow};
+use std::fs::{File, copy, create_dir_all, read_to_string};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use toml::Value;
+use toml::value::Table;
+
+/// Name of the index at the root of a bundle
+pub const INDEX_FILE: &str = "index.toml";
+
+/// A file carried by a bundle, relative to the mirror root
+pub struct BundleFile {
+    pub path: String,
+    pub sha256: String,
+    pub size: u64,
+}
+
+/// Index of a bundle, listing its contents and their hashes
+pub struct Index {
+    /// Unique identifier of the bundle
+    pub id: String,
+    /// Bundle this one is a delta of
+    pub base_id: Option<String>,
+    /// Date since which this bundle is a delta
+    pub base_date: Option<String>,
+    /// Date of the newest manifest in the bundle
+    pub newest_date: Option<String>,
+    /// When the bundle was created, in RFC 3339
+    pub created: String,
+    /// Where the exporting mirror is served, which manifest urls point to
+    pub mirror_url: String,
+    /// Manifests in the bundle, also listed in files
+    pub manifests: Vec<String>,
+    pub files: Vec<BundleFile>,
+}
+
+impl Index {
+    pub fn to_toml(&self) -> String {
+        let mut table = Table::new();
+        table.insert("format-version".into(), Value::String("1".into()));
+        table.insert("id".into(), Value::String(self.id.clone()));
+        for (key, value) in [
+            ("base-id", &self.base_id),
+            ("base-date", &self.base_date),
+            ("newest-date", &self.newest_date),
+        ] {
+            if let Some(value) = value {
+                table.insert(key.into(), Value::String(value.clone()));
+            }
+        }
+        table.insert("created".into(), Value::String(self.created.clone()));
+        table.insert("mirror-url".into(), Value::String(self.mirror_url.clone()));
+        table.insert(
+            "manifests".into(),
+            Value::Array(
+                self.manifests
+                    .iter()
+                    .map(|path| Value::String(path.clone()))
+                    .collect(),
+            ),
+        );
+        let files = self
+            .files
+            .iter()
+            .map(|file| {
+                let mut entry = Table::new();
+                entry.insert("path".into(), Value::String(file.path.clone()));
+                entry.insert("sha256".into(), Value::String(file.sha256.clone()));
+                entry.insert("size".into(), Value::Integer(file.size as i64));
+                Value::Table(entry)
+            })
+            .collect();
+        table.insert("file".into(), Value::Array(files));
+        toml::to_string(&Value::Table(table)).unwrap()
+    }
+
+    pub fn parse(data: &str) -> Result<Self, Error> {
+        let value = data.parse::<Value>()?;
+        if value.get("format-version").and_then(Value::as_str) != Some("1") {
+            return Err(anyhow!("Unsupported bundle format"));
+        }
+        let optional = |key: &str| value.get(key).and_then(Value::as_str).map(String::from);
+        let string =
+            |key: &str| optional(key).ok_or_else(|| anyhow!("Missing {} in bundle index", key));
+
+        let mut manifests = Vec::new();
+        for path in value
+            .get("manifests")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+        {
+            manifests.push(
+                path.as_str()
+                    .ok_or_else(|| anyhow!("Invalid manifest in bundle index"))?
+                    .to_string(),
+            );
+        }
+
+        let mut files = Vec::new();
+        for file in value
+            .get("file")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+        {
+            let field = |key: &str| file.get(key).ok_or_else(|| anyhow!("Invalid file entry"));
+            files.push(BundleFile {
+                path: field("path")?
+                    .as_str()
+                    .ok_or_else(|| anyhow!("Invalid file path"))?
+                    .to_string(),
+                sha256: field("sha256")?
+                    .as_str()
+                    .ok_or_else(|| anyhow!("Invalid file hash"))?
+                    .to_string(),
+                size: field("size")?
+                    .as_integer()
+                    .ok_or_else(|| anyhow!("Invalid file size"))? as u64,
+            });
+        }
+
+        Ok(Index {
+            id: string("id")?,
+            base_id: optional("base-id"),
+            base_date: optional("base-date"),
+            newest_date: optional("newest-date"),
+            created: string("created")?,
+            mirror_url: string("mirror-url")?,
+            manifests,
+            files,
+        })
+    }
+}
+
+/// Read the index of a bundle, given the bundle itself or its index file
+pub fn read_index(path: &Path) -> Result<Index, Error> {
+    let data = if path.is_dir() {
+        read_to_string(path.join(INDEX_FILE))?
+    } else if path.extension().is_some_and(|ext| ext == "tar") {
+        let mut archive = tar::Archive::new(File::open(path)?);
+        let mut data = None;
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            if entry.path()? == Path::new(INDEX_FILE) {
+                let mut content = String::new();
+                entry.read_to_string(&mut content)?;
+                data = Some(content);
+                break;
+            }
+        }
+        data.ok_or_else(|| anyhow!("No {} in {}", INDEX_FILE, path.display()))?
+    } else {
+        read_to_string(path)?
+    };
+    Index::parse(&data)
+}
+
+/// Content of a file to put into a bundle
+pub enum Source {
+    File(PathBuf),
+    Data(Vec<u8>),
+}
+
+/// Destination of a bundle, either a directory or a tarball
+pub enum BundleWriter {
+    Dir(PathBuf),
+    Tar(tar::Builder<File>),
+}
+
+impl BundleWriter {
+    /// Write a tarball if the output ends with .tar, a directory otherwise
+    pub fn create(output: &Path) -> Result<Self, Error> {
+        if output.extension().is_some_and(|ext| ext == "tar") {
+            Ok(BundleWriter::Tar(tar::Builder::new(File::create(output)?)))
+        } else {
+            create_dir_all(output)?;
+            Ok(BundleWriter::Dir(output.to_path_buf()))
+        }
+    }
+
+    pub fn add(&mut self, path: &str, source: &Source) -> Result<(), Error> {
+        match self {
+            BundleWriter::Dir(root) => {
+                let dest = root.join(path);
+                create_dir_all(dest.parent().unwrap())?;
+                match source {
+                    Source::File(file) => {
+                        copy(file, dest)?;
+                    }
+                    Source::Data(data) => File::create(dest)?.write_all(data)?,
+                }
+            }
+            BundleWriter::Tar(builder) => match source {
+                Source::File(file) => builder.append_path_with_name(file, path)?,
+                Source::Data(data) => {
+                    let mut header = tar::Header::new_gnu();
+                    header.set_size(data.len() as u64);
+                    header.set_mode(0o644);
+                    header.set_mtime(chrono::Utc::now().timestamp() as u64);
+                    builder.append_data(&mut header, path, data.as_slice())?;
+                }
+            },
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<(), Error> {
+        if let BundleWriter::Tar(mut builder) = self {
+            builder.finish()?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,86 @@
+use crate::quota::parse_size;
+use crate::rate::Schedule;
+use anyhow::{Error, anyhow};
+use chrono::NaiveTime;
+use std::fs::read_to_string;
+use std::path::Path;
+use toml::Value;
+
+/// Settings read from the file given with --config
+#[derive(Default)]
+pub struct Config {
+    /// Bytes per second, overridden by --limit-rate
+    pub limit_rate: Option<u64>,
+    pub schedules: Vec<Schedule>,
+    /// Each overridden by the command line option of the same name
+    pub proxy: Option<String>,
+    pub no_proxy: Option<String>,
+    pub ca_cert: Option<String>,
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+}
+
+/// Parse a rate like 20M, or "unlimited"
+fn parse_rate(rate: &Value) -> Result<Option<u64>, Error> {
+    match rate.as_str() {
+        Some("unlimited") => Ok(None),
+        Some(rate) => Ok(Some(parse_size(rate)?)),
+        None => Err(anyhow!("Invalid rate {}", rate)),
+    }
+}
+
+fn parse_time(table: &Value, key: &str) -> Result<NaiveTime, Error> {
+    let time = table
+        .get(key)
+        .and_then(Value::as_str)
+        .ok_or_else(|| anyhow!("Missing {} in schedule", key))?;
+    NaiveTime::parse_from_str(time, "%H:%M").map_err(|e| anyhow!("Invalid time {}: {}", time, e))
+}
+
+pub fn read_config(path: &Path) -> Result<Config, Error> {
+    let data = read_to_string(path)
+        .map_err(|e| anyhow!("Unable to read config file {}: {}", path.display(), e))?;
+    let value = data
+        .parse::<Value>()
+        .map_err(|e| anyhow!("Invalid config file {}: {}", path.display(), e))?;
+
+    let string = |key: &str| -> Result<Option<String>, Error> {
+        match value.get(key) {
+            Some(v) => Ok(Some(
+                v.as_str()
+                    .ok_or_else(|| anyhow!("{} must be a string", key))?
+                    .to_string(),
+            )),
+            None => Ok(None),
+        }
+    };
+
+    let mut config = Config {
+        proxy: string("proxy")?,
+        no_proxy: string("no-proxy")?,
+        ca_cert: string("ca-cert")?,
+        client_cert: string("client-cert")?,
+        client_key: string("client-key")?,
+        ..Default::default()
+    };
+    if let Some(rate) = value.get("limit-rate") {
+        config.limit_rate = parse_rate(rate)?;
+    }
+    for schedule in value
+        .get("schedule")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+    {
+        config.schedules.push(Schedule {
+            from: parse_time(schedule, "from")?,
+            to: parse_time(schedule, "to")?,
+            limit: parse_rate(
+                schedule
+                    .get("limit-rate")
+                    .ok_or_else(|| anyhow!("Missing limit-rate in schedule"))?,
+            )?,
+        });
+    }
+    Ok(config)
+}
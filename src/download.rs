@@ -0,0 +1,286 @@
+use crate::encode_path;
+use crate::rate::RateLimiter;
+use anyhow::{Error, anyhow};
+use indicatif::{ProgressBar, ProgressStyle};
+use reqwest::StatusCode;
+use reqwest::blocking::{Client, Response};
+use reqwest::header::{CONTENT_LENGTH, RETRY_AFTER};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::fmt;
+use std::fs::{File, copy, create_dir_all, hard_link, metadata, remove_file};
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use url::Url;
+
+/// Fetches files from upstream urls, either http(s):// or file://
+pub struct Downloader {
+    /// Hardlink files from file:// upstreams instead of copying them
+    pub hardlink: bool,
+    /// Shared by all downloads, so that connections are reused
+    pub client: Client,
+    /// Abort transfers slower than this many bytes per second over this duration
+    pub low_speed: Option<(u64, Duration)>,
+    /// How many times a request is attempted before giving up
+    pub retries: u32,
+    /// Delay before the first retry, doubled for each of the next ones
+    pub retry_delay: Duration,
+    /// Bandwidth shared by all downloads from http(s) upstreams
+    pub rate: RateLimiter,
+}
+
+/// Parse a delay in seconds, e.g. 0.5
+pub fn parse_delay(delay: &str) -> Result<Duration, Error> {
+    let secs: f64 = delay
+        .trim()
+        .parse()
+        .map_err(|e| anyhow!("Invalid delay {}: {}", delay, e))?;
+    Duration::try_from_secs_f64(secs).map_err(|e| anyhow!("Invalid delay {}: {}", delay, e))
+}
+
+/// The upstream does not have the file
+#[derive(Debug)]
+pub struct NotFound {
+    pub url: String,
+}
+
+impl fmt::Display for NotFound {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Not found: {}", self.url)
+    }
+}
+
+impl std::error::Error for NotFound {}
+
+/// Longest delay between two attempts, unless the upstream asks for more
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Longest Retry-After honoured
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(600);
+
+/// Whether a request failing with this status may succeed later
+fn is_retryable(status: StatusCode) -> bool {
+    status.is_server_error()
+        || status == StatusCode::TOO_MANY_REQUESTS
+        || status == StatusCode::REQUEST_TIMEOUT
+}
+
+/// How long the upstream asks us to wait, either in seconds or until a date
+fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?;
+    let delay = match value.trim().parse::<u64>() {
+        Ok(secs) => Duration::from_secs(secs),
+        Err(_) => (chrono::DateTime::parse_from_rfc2822(value).ok()?.to_utc() - chrono::Utc::now())
+            .to_std()
+            .unwrap_or_default(),
+    };
+    Some(delay.min(MAX_RETRY_AFTER))
+}
+
+fn local_path(url: &str) -> Result<PathBuf, Error> {
+    Url::parse(url)
+        .ok()
+        .and_then(|url| url.to_file_path().ok())
+        .ok_or_else(|| anyhow!("Invalid file url {}", url))
+}
+
+impl Downloader {
+    /// Wait before attempting again, exponentially longer after each attempt,
+    /// with jitter so that parallel mirrors do not retry in lockstep
+    fn backoff(&self, attempts: u32, retry_after: Option<Duration>) {
+        let delay = retry_after.unwrap_or_else(|| {
+            let delay = self
+                .retry_delay
+                .saturating_mul(1 << (attempts - 1).min(16))
+                .min(MAX_RETRY_DELAY);
+            let mut random = [0u8; 4];
+            SystemRandom::new().fill(&mut random).unwrap();
+            delay / 2 + delay.mul_f64(u32::from_le_bytes(random) as f64 / u32::MAX as f64) / 2
+        });
+        println!("Waiting {:.1}s before retrying", delay.as_secs_f64());
+        sleep(delay);
+    }
+
+    fn copy_local(&self, url: &str, file_path: &Path) -> Result<(), Error> {
+        let source = local_path(url)?;
+        if !source.is_file() {
+            return Err(NotFound {
+                url: source.display().to_string(),
+            }
+            .into());
+        }
+        // Hardlinks across filesystems fail, fall back to copying
+        if !(self.hardlink && hard_link(&source, file_path).is_ok()) {
+            copy(&source, file_path)?;
+        }
+        Ok(())
+    }
+
+    /// Size of a file at the upstream without downloading it
+    pub fn size(&self, upstream_url: &str, path: &str) -> Result<u64, Error> {
+        let url = format!("{}{}", upstream_url, encode_path(path));
+        if upstream_url.starts_with("file://") {
+            return Ok(metadata(local_path(&url)?)?.len());
+        }
+        let response = self.client.head(&url).send()?;
+        if !response.status().is_success() {
+            return Err(anyhow!("HTTP {} for {}", response.status(), url));
+        }
+        response
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|len| len.to_str().ok())
+            .and_then(|len| len.parse().ok())
+            .ok_or_else(|| anyhow!("Unknown size of {}", url))
+    }
+
+    /// Download `path` relative to the upstream url into `dir`
+    pub fn download(&self, upstream_url: &str, dir: &str, path: &str) -> Result<PathBuf, Error> {
+        let manifest = format!("{}{}", upstream_url, encode_path(path));
+        let mut response;
+        let mirror = Path::new(dir);
+        let file_path = mirror.join(path);
+        create_dir_all(file_path.parent().unwrap())?;
+        // Never write through an existing file, it may be hardlinked to an upstream
+        if file_path.exists() {
+            remove_file(&file_path)?;
+        }
+
+        if upstream_url.starts_with("file://") {
+            self.copy_local(&manifest, &file_path)?;
+            println!("File /{} copied", path);
+            return Ok(file_path);
+        }
+
+        // Only created once the upstream answers, so that errors leave nothing behind
+        let mut dest: Option<File> = None;
+        let mut attempts = 0;
+
+        'outer: loop {
+            attempts += 1;
+            let res = match self.client.get(&manifest).send() {
+                Ok(res) if res.status().is_success() => Ok(res),
+                Ok(res) if res.status() == StatusCode::NOT_FOUND => {
+                    return Err(NotFound { url: manifest }.into());
+                }
+                Ok(res) if is_retryable(res.status()) => {
+                    Err((anyhow!("HTTP {}", res.status()), retry_after(&res)))
+                }
+                Ok(res) => return Err(anyhow!("HTTP {} for {}", res.status(), manifest)),
+                Err(e) if e.is_builder() || e.is_redirect() => return Err(e.into()),
+                Err(e) => Err((e.into(), None)),
+            };
+            response = match res {
+                Ok(res) => res,
+                Err((e, delay)) => {
+                    if attempts >= self.retries {
+                        return Err(anyhow!(
+                            "Failed to download after {} attempts: {}",
+                            attempts,
+                            e
+                        ));
+                    }
+                    println!("Attempt {} failed: {}. Retrying...", attempts, e);
+                    self.backoff(attempts, delay);
+                    continue 'outer;
+                }
+            };
+
+            let dest = match &mut dest {
+                Some(dest) => {
+                    dest.rewind()?;
+                    dest.set_len(0)?;
+                    dest
+                }
+                None => dest.insert(File::create(&file_path)?),
+            };
+
+            println!("File /{} downloading", path);
+            // Chunked responses have no length, they are read until the end
+            let length = response.content_length();
+            let pb = match length {
+                Some(length) => {
+                    let pb = ProgressBar::new(length);
+                    pb.set_style(ProgressStyle::default_bar()
+        .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} (ETA {eta_precise})")?
+        .progress_chars("#>-"));
+                    pb
+                }
+                None => {
+                    let pb = ProgressBar::new_spinner();
+                    pb.set_style(ProgressStyle::default_spinner().template(
+                        "{spinner:.green} [{elapsed_precise}] {bytes} ({bytes_per_sec})",
+                    )?);
+                    pb
+                }
+            };
+
+            let mut buffer = [0u8; 4096];
+            let mut read = 0;
+            let mut window = (Instant::now(), 0);
+
+            while length.is_none_or(|length| read < length) {
+                let res = response.read(&mut buffer).and_then(|len| {
+                    if len == 0
+                        && let Some(length) = length
+                    {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::UnexpectedEof,
+                            format!("connection closed after {} of {} bytes", read, length),
+                        ));
+                    }
+                    if let Some((limit, time)) = self.low_speed {
+                        let elapsed = window.0.elapsed();
+                        if elapsed >= time {
+                            let speed = (read + len as u64 - window.1) / elapsed.as_secs().max(1);
+                            if speed < limit {
+                                return Err(std::io::Error::new(
+                                    std::io::ErrorKind::TimedOut,
+                                    format!(
+                                        "transfer slower than {} bytes/s for {}s",
+                                        limit,
+                                        time.as_secs()
+                                    ),
+                                ));
+                            }
+                            window = (Instant::now(), read + len as u64);
+                        }
+                    }
+                    Ok(len)
+                });
+                match res {
+                    Ok(0) => break,
+                    Ok(len) => {
+                        self.rate.consume(len);
+                        dest.write_all(&buffer[..len])?;
+                        read += len as u64;
+                        pb.set_position(read);
+                    }
+                    Err(e) => {
+                        if attempts >= self.retries {
+                            return Err(anyhow!(
+                                "Failed to read response after {} attempts: {}",
+                                attempts,
+                                e
+                            ));
+                        }
+                        println!(
+                            "Attempt {} to read response failed: {}. Retrying...",
+                            attempts, e
+                        );
+                        pb.finish_and_clear();
+                        self.backoff(attempts, None);
+                        continue 'outer;
+                    }
+                }
+            }
+
+            pb.finish_and_clear();
+            println!("File /{} downloaded", path);
+            break;
+        }
+
+        Ok(mirror.join(path))
+    }
+}
@@ -0,0 +1,326 @@
+use crate::bundle::{BundleFile, BundleWriter, INDEX_FILE, Index, Source, read_index};
+use crate::toolchain::Toolchain;
+use crate::{file_sha256, mirrored_artifacts};
+use anyhow::{Error, anyhow};
+use chrono::NaiveDate;
+use clap::Args;
+use ring::digest;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{File, metadata, read_dir, read_to_string};
+use std::io::Write;
+use std::path::Path;
+use toml::Value;
+
+#[derive(Args)]
+pub struct ExportArgs {
+    /// Where mirror files are stored
+    #[arg(short, long, default_value = "./mirror")]
+    mirror: String,
+
+    /// Where sync stored original files, including the upstream rustup-init.sh
+    /// when the mirrored one was rewritten with --rewrite-installer
+    #[arg(long, default_value = "./orig")]
+    orig: String,
+
+    /// Where mirror is served
+    #[arg(short, long, default_value = "http://127.0.0.1:8000")]
+    url: String,
+
+    /// Which release channel(s) to export, e.g. stable,nightly
+    #[arg(short, long, value_delimiter = ',')]
+    channels: Vec<String>,
+
+    /// Which toolchains to export, e.g. nightly-2024-03-01,1.74.1
+    #[arg(long, value_delimiter = ',')]
+    toolchains: Vec<Toolchain>,
+
+    /// Which targets to export, all mirrored targets if omitted
+    #[arg(short, long, value_delimiter = ',')]
+    targets: Vec<String>,
+
+    /// Which components to export, e.g. rustc,cargo,rust-std, all if omitted
+    #[arg(long, value_delimiter = ',')]
+    components: Vec<String>,
+
+    /// Where to write the bundle, a tarball if it ends with .tar, a directory otherwise
+    #[arg(short, long)]
+    output: String,
+
+    /// Only export what was added since a date included, e.g. 2024-03-01,
+    /// or since a previous bundle, given the bundle or its index
+    #[arg(long)]
+    since: Option<String>,
+
+    /// Also write the index of the bundle here, to use with --since next time
+    #[arg(long)]
+    state_file: Option<String>,
+}
+
+/// Base of a delta bundle
+enum Since {
+    Date(NaiveDate),
+    Bundle(Index),
+}
+
+impl Since {
+    fn parse(since: &str) -> Result<Self, Error> {
+        match NaiveDate::parse_from_str(since, "%Y-%m-%d") {
+            Ok(date) => Ok(Since::Date(date)),
+            Err(_) => Ok(Since::Bundle(read_index(Path::new(since)).map_err(
+                |e| anyhow!("--since {} is neither a date nor a bundle: {}", since, e),
+            )?)),
+        }
+    }
+}
+
+/// Date of the directory of a file under dist/
+fn dist_date(path: &str) -> Option<NaiveDate> {
+    let date = path.strip_prefix("dist/")?.split('/').next()?;
+    NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()
+}
+
+fn data_sha256(data: &[u8]) -> String {
+    hex::encode(digest::digest(&digest::SHA256, data))
+}
+
+/// Mark the packages and targets not exported as unavailable
+fn filter_manifest(value: &mut Value, targets: &[String], components: &[String]) {
+    let pkgs = match value.get_mut("pkg").and_then(Value::as_table_mut) {
+        Some(pkgs) => pkgs,
+        None => return,
+    };
+    for (pkg_name, pkg) in pkgs.iter_mut() {
+        let wanted_pkg = components.is_empty() || components.contains(pkg_name);
+        let pkg_targets = match pkg.get_mut("target").and_then(Value::as_table_mut) {
+            Some(pkg_targets) => pkg_targets,
+            None => continue,
+        };
+        for (target, pkg_target) in pkg_targets.iter_mut() {
+            let wanted =
+                wanted_pkg && (targets.is_empty() || targets.contains(target) || target == "*");
+            if !wanted && let Some(table) = pkg_target.as_table_mut() {
+                table.insert("available".into(), Value::Boolean(false));
+            }
+        }
+    }
+}
+
+/// Add a file of the mirror and its checksum file, if any, to the bundle
+fn add_mirrored(entries: &mut BTreeMap<String, Source>, mirror: &Path, path: &str) {
+    for path in [path.to_string(), format!("{}.sha256", path)] {
+        let file = mirror.join(&path);
+        if file.is_file() {
+            entries.insert(path, Source::File(file));
+        }
+    }
+}
+
+/// Add a filtered copy of a manifest and all its artifacts to the bundle
+fn add_manifest(
+    args: &ExportArgs,
+    entries: &mut BTreeMap<String, Source>,
+    manifests: &mut Vec<(String, String)>,
+    names: &[String],
+) -> Result<(), Error> {
+    let mirror = Path::new(&args.mirror);
+    let name = &names[0];
+    let data = read_to_string(mirror.join(name))
+        .map_err(|e| anyhow!("Unable to read /{} from mirror: {}", name, e))?;
+    let mut value = data.parse::<Value>()?;
+    filter_manifest(&mut value, &args.targets, &args.components);
+
+    for file in mirrored_artifacts(&value, &args.url) {
+        if !mirror.join(&file).is_file() {
+            return Err(anyhow!("/{} referenced by /{} is missing", file, name));
+        }
+        add_mirrored(entries, mirror, &file);
+    }
+
+    let date = value
+        .get("date")
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+        .to_string();
+    let output = toml::to_string(&value)?;
+    let sha256 = data_sha256(output.as_bytes());
+    for name in names {
+        let fname = Path::new(name).file_name().unwrap().to_string_lossy();
+        entries.insert(
+            format!("{}.sha256", name),
+            Source::Data(format!("{}  {}", sha256, fname).into_bytes()),
+        );
+        entries.insert(name.clone(), Source::Data(output.clone().into_bytes()));
+        manifests.push((name.clone(), date.clone()));
+    }
+    Ok(())
+}
+
+/// Add rustup-init binaries of the exported targets and the self update manifest
+fn add_rustup(args: &ExportArgs, entries: &mut BTreeMap<String, Source>) {
+    let mirror = Path::new(&args.mirror);
+    let targets: Vec<String> = if args.targets.is_empty() {
+        read_dir(mirror.join("rustup/dist"))
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .collect()
+    } else {
+        args.targets.clone()
+    };
+
+    let version = read_to_string(mirror.join("rustup/release-stable.toml"))
+        .ok()
+        .and_then(|data| data.parse::<Value>().ok())
+        .and_then(|value| value.get("version")?.as_str().map(String::from));
+    if version.is_some() {
+        add_mirrored(entries, mirror, "rustup/release-stable.toml");
+    }
+    // A rewritten installer points at this mirror, ship the upstream one instead
+    let orig = Path::new(&args.orig);
+    if orig.join("rustup/rustup-init.sh").is_file() {
+        add_mirrored(entries, orig, "rustup/rustup-init.sh");
+    } else {
+        add_mirrored(entries, mirror, "rustup/rustup-init.sh");
+    }
+
+    for target in targets {
+        for ext in ["", ".exe"] {
+            add_mirrored(
+                entries,
+                mirror,
+                &format!("rustup/dist/{}/rustup-init{}", target, ext),
+            );
+            if let Some(version) = &version {
+                add_mirrored(
+                    entries,
+                    mirror,
+                    &format!("rustup/archive/{}/{}/rustup-init{}", version, target, ext),
+                );
+            }
+        }
+    }
+}
+
+pub fn run(args: ExportArgs) -> Result<(), Error> {
+    if args.channels.is_empty() && args.toolchains.is_empty() {
+        return Err(anyhow!("Nothing to export, use --channels or --toolchains"));
+    }
+
+    let mirror = Path::new(&args.mirror);
+    let mut entries = BTreeMap::new();
+    let mut manifests = Vec::new();
+
+    for channel in args.channels.iter() {
+        let name = format!("dist/channel-rust-{}.toml", channel);
+        let date = read_to_string(mirror.join(&name))
+            .ok()
+            .and_then(|data| data.parse::<Value>().ok())
+            .and_then(|value| value.get("date")?.as_str().map(String::from))
+            .ok_or_else(|| anyhow!("Channel {} is not in the mirror", channel))?;
+        let alt_name = format!("dist/{}/channel-rust-{}.toml", date, channel);
+        add_manifest(&args, &mut entries, &mut manifests, &[name, alt_name])?;
+    }
+    for toolchain in args.toolchains.iter() {
+        add_manifest(
+            &args,
+            &mut entries,
+            &mut manifests,
+            &[toolchain.manifest_name()],
+        )?;
+    }
+    add_rustup(&args, &mut entries);
+
+    let since = args.since.as_deref().map(Since::parse).transpose()?;
+    if let Some(Since::Date(date)) = since {
+        // Manifests and artifacts are dated, rustup files only have their mtime.
+        // The day itself is sent again, import skips the files it already has
+        let dated: HashMap<&str, &str> = manifests
+            .iter()
+            .map(|(name, date)| (name.as_str(), date.as_str()))
+            .collect();
+        let midnight = date.and_hms_opt(0, 0, 0).unwrap().and_utc().timestamp();
+        entries.retain(|path, source| {
+            let name = path.strip_suffix(".sha256").unwrap_or(path);
+            if let Some(manifest_date) = dated.get(name) {
+                return NaiveDate::parse_from_str(manifest_date, "%Y-%m-%d")
+                    .is_ok_and(|manifest_date| manifest_date >= date);
+            }
+            if let Some(dir_date) = dist_date(path) {
+                return dir_date >= date;
+            }
+            match source {
+                Source::File(file) => metadata(file)
+                    .and_then(|m| m.modified())
+                    .map(|modified| chrono::DateTime::<chrono::Utc>::from(modified).timestamp())
+                    .is_ok_and(|modified| modified >= midnight),
+                Source::Data(_) => true,
+            }
+        });
+    }
+
+    let mut files = Vec::new();
+    for (path, source) in entries.iter() {
+        let (sha256, size) = match source {
+            Source::File(file) => (file_sha256(file).unwrap(), metadata(file)?.len()),
+            Source::Data(data) => (data_sha256(data), data.len() as u64),
+        };
+        files.push(BundleFile {
+            path: path.clone(),
+            sha256,
+            size,
+        });
+    }
+
+    if let Some(Since::Bundle(ref base)) = since {
+        let known: HashSet<(&str, &str)> = base
+            .files
+            .iter()
+            .map(|file| (file.path.as_str(), file.sha256.as_str()))
+            .collect();
+        files.retain(|file| !known.contains(&(file.path.as_str(), file.sha256.as_str())));
+        let kept: HashSet<String> = files.iter().map(|file| file.path.clone()).collect();
+        entries.retain(|path, _| kept.contains(path));
+    }
+    manifests.retain(|(name, _)| entries.contains_key(name));
+
+    let created = chrono::Local::now().to_rfc3339();
+    let mut id = digest::Context::new(&digest::SHA256);
+    id.update(created.as_bytes());
+    for file in files.iter() {
+        id.update(format!("{} {}\n", file.path, file.sha256).as_bytes());
+    }
+    let index = Index {
+        id: hex::encode(id.finish()),
+        base_id: match since {
+            Some(Since::Bundle(ref base)) => Some(base.id.clone()),
+            _ => None,
+        },
+        base_date: match since {
+            Some(Since::Date(date)) => Some(date.to_string()),
+            _ => None,
+        },
+        newest_date: manifests.iter().map(|(_, date)| date.clone()).max(),
+        created,
+        mirror_url: args.url.clone(),
+        manifests: manifests.into_iter().map(|(name, _)| name).collect(),
+        files,
+    };
+
+    println!("Writing {} files to {}", entries.len() + 1, args.output);
+    let mut writer = BundleWriter::create(Path::new(&args.output))?;
+    writer.add(INDEX_FILE, &Source::Data(index.to_toml().into_bytes()))?;
+    for (path, source) in entries.iter() {
+        writer.add(path, source)?;
+    }
+    writer.finish()?;
+    if let Some(state_file) = &args.state_file {
+        File::create(state_file)?.write_all(index.to_toml().as_bytes())?;
+    }
+    println!(
+        "Exported {} manifests, bundle id {}",
+        index.manifests.len(),
+        index.id
+    );
+    Ok(())
+}
@@ -0,0 +1,687 @@
+use crate::quota::dir_size;
+use crate::toolchain::Toolchain;
+use crate::{RELEASE_CHANNELS, mirrored_artifacts, normalize_path, read_manifest};
+use anyhow::{Error, anyhow};
+use chrono::{Duration, NaiveDate};
+use std::collections::{HashMap, HashSet};
+use std::fs::{read_dir, remove_dir_all, remove_file};
+use std::path::{Path, PathBuf};
+use toml::Value;
+
+/// Which nightly toolchains survive garbage collection
+pub struct RetentionPolicy {
+    /// Nightly older than this date may be deleted
+    pub cutoff: Option<NaiveDate>,
+    /// Always keep at least this many of the newest nightly
+    pub keep_nightlies: Option<usize>,
+    /// Toolchains that are never deleted
+    pub pinned: HashSet<Toolchain>,
+    /// Directories under dist/ that are never touched
+    pub protected_dirs: HashSet<String>,
+    /// Keep this many of the newest rustup versions under rustup/archive/, all if None
+    pub keep_rustup_versions: Option<usize>,
+    /// Upstream manifests which failed to sync, whose artifacts are kept for the next run
+    pub unfinished: Vec<Value>,
+}
+
+impl RetentionPolicy {
+    pub fn new(today: NaiveDate, gc_days: Option<i64>, keep_nightlies: Option<usize>) -> Self {
+        RetentionPolicy {
+            cutoff: gc_days.map(|days| today - Duration::days(days)),
+            keep_nightlies,
+            pinned: HashSet::new(),
+            protected_dirs: HashSet::new(),
+            keep_rustup_versions: None,
+            unfinished: Vec::new(),
+        }
+    }
+
+    /// Compute the set of nightly dates to keep, given the dates found in the mirror
+    fn retained(&self, nightly_dates: &[NaiveDate]) -> Option<HashSet<NaiveDate>> {
+        if self.cutoff.is_none() && self.keep_nightlies.is_none() {
+            // No policy, keep everything
+            return None;
+        }
+
+        let mut dates = nightly_dates.to_vec();
+        dates.sort_unstable_by(|a, b| b.cmp(a));
+
+        let mut retained: HashSet<NaiveDate> = self
+            .pinned
+            .iter()
+            .filter_map(Toolchain::nightly_date)
+            .collect();
+        if let Some(keep) = self.keep_nightlies {
+            retained.extend(dates.iter().take(keep));
+        }
+        if let Some(cutoff) = self.cutoff {
+            retained.extend(dates.iter().filter(|date| **date >= cutoff));
+        }
+        Some(retained)
+    }
+}
+
+/// Channel of a manifest file name, e.g. `channel-rust-nightly.toml`
+fn manifest_channel(file_name: &str) -> Option<&str> {
+    file_name
+        .strip_prefix("channel-rust-")
+        .and_then(|name| name.strip_suffix(".toml"))
+}
+
+/// Read manifests directly under dist/ of release channels and pinned versions, which are
+/// always retained, and tell which dated stable/beta manifests are current.
+/// Also lists the other manifests directly under dist/
+fn scan_current(
+    dist: &Path,
+    policy: &RetentionPolicy,
+) -> (Vec<Value>, HashMap<String, String>, Vec<PathBuf>) {
+    let mut manifests = Vec::new();
+    let mut current_dates = HashMap::new();
+    let mut unretained = Vec::new();
+    for entry in read_dir(dist).expect("Unable to read dist dir") {
+        let entry = entry.unwrap();
+        let fname = entry.file_name().to_string_lossy().to_string();
+        let channel = match manifest_channel(&fname) {
+            Some(channel) if entry.file_type().unwrap().is_file() => channel,
+            _ => continue,
+        };
+        if !RELEASE_CHANNELS.contains(&channel)
+            && !policy
+                .pinned
+                .contains(&Toolchain::Version(channel.to_string()))
+        {
+            unretained.push(entry.path());
+            continue;
+        }
+        if let Some(value) = read_manifest(&entry.path()) {
+            if let Some(date) = value.get("date").and_then(Value::as_str) {
+                current_dates.insert(fname.clone(), date.to_string());
+            }
+            manifests.push(value);
+        }
+    }
+    (manifests, current_dates, unretained)
+}
+
+/// List the dated directories under dist/, oldest first
+fn scan_date_dirs(dist: &Path, policy: &RetentionPolicy) -> Vec<(NaiveDate, PathBuf)> {
+    let mut date_dirs = Vec::new();
+    for date_dir in read_dir(dist).expect("Unable to read dist dir") {
+        let date_dir = date_dir.unwrap();
+        if !date_dir.file_type().unwrap().is_dir() {
+            // Is metadata
+            continue;
+        }
+        let dir_name = date_dir.file_name().to_string_lossy().to_string();
+        if policy.protected_dirs.contains(&dir_name) {
+            continue;
+        }
+        match NaiveDate::parse_from_str(&dir_name, "%Y-%m-%d") {
+            Ok(date) => date_dirs.push((date, date_dir.path())),
+            Err(_) => println!("Unknown directory {}, skipping", date_dir.path().display()),
+        }
+    }
+    date_dirs.sort();
+    date_dirs
+}
+
+/// Garbage collect dated manifests according to the retention policy,
+/// then every artifact not referenced by a retained manifest.
+/// Returns the deleted files, relative to the mirror root
+pub fn collect(mirror_path: &str, mirror_url: &str, policy: &RetentionPolicy) -> Vec<String> {
+    let mut deleted = Vec::new();
+    let dist = Path::new(mirror_path).join("dist");
+    if !dist.exists() {
+        // Nothing mirrored yet
+        return deleted;
+    }
+    let (mut retained_manifests, current_dates, unretained) = scan_current(&dist, policy);
+    retained_manifests.extend(policy.unfinished.iter().cloned());
+    for manifest in unretained {
+        println!("Deleting file {}[.sha256]", manifest.display());
+        remove_file(&manifest).unwrap();
+        let mut hash = manifest.clone().into_os_string();
+        hash.push(".sha256");
+        let _ = remove_file(hash);
+        if let Ok(path) = manifest.strip_prefix(mirror_path) {
+            deleted.push(path.to_string_lossy().into_owned());
+        }
+    }
+    let date_dirs = scan_date_dirs(&dist, policy);
+
+    let nightly_dates: Vec<NaiveDate> = date_dirs
+        .iter()
+        .filter(|(_, path)| path.join("channel-rust-nightly.toml").exists())
+        .map(|(date, _)| *date)
+        .collect();
+    let retained_nightlies = policy.retained(&nightly_dates);
+
+    // Decide which dated manifests survive
+    let mut kept_manifests = HashSet::new();
+    for (date, date_dir) in date_dirs.iter() {
+        for file in read_dir(date_dir).expect("inner dir") {
+            let file = file.unwrap();
+            let fname = file.file_name().to_string_lossy().to_string();
+            let channel = match manifest_channel(&fname) {
+                Some(channel) => channel,
+                None => continue,
+            };
+
+            let pinned = policy.pinned.contains(&Toolchain::Dated {
+                channel: channel.to_string(),
+                date: *date,
+            });
+            let keep = if pinned {
+                true
+            } else if channel == "nightly" {
+                match retained_nightlies {
+                    Some(ref retained) => retained.contains(date),
+                    None => true,
+                }
+            } else if RELEASE_CHANNELS.contains(&channel) {
+                // Keep the dated copy of the current stable/beta
+                current_dates.get(&fname) == Some(&date.to_string())
+            } else {
+                false
+            };
+
+            if keep {
+                if let Some(value) = read_manifest(&file.path()) {
+                    retained_manifests.push(value);
+                }
+                kept_manifests.insert(normalize_path(&file.path()));
+            }
+        }
+    }
+
+    // All files referenced by retained manifests
+    let mirror = Path::new(mirror_path);
+    let referenced: HashSet<PathBuf> = retained_manifests
+        .iter()
+        .flat_map(|value| mirrored_artifacts(value, mirror_url))
+        .map(|file| normalize_path(&mirror.join(file)))
+        .collect();
+
+    for (_, date_dir) in date_dirs {
+        // Is there anyone left?
+        let mut perserve_dir = false;
+
+        for file in read_dir(&date_dir).expect("inner dir") {
+            let file = file.unwrap();
+            let fname = file.file_name();
+            let fname = fname.to_string_lossy();
+            if fname.ends_with(".sha256") {
+                // Is an hash, will be deleted alongside the hashed file
+                continue;
+            }
+            if !file.file_type().unwrap().is_file() {
+                println!("Unknown entry {}, skipping", file.path().display());
+                perserve_dir = true;
+                continue;
+            }
+
+            let canonicalized = file.path().canonicalize().unwrap();
+            let normalized = normalize_path(&file.path());
+
+            let to_be_deleted =
+                !(referenced.contains(&normalized) || kept_manifests.contains(&normalized));
+
+            if to_be_deleted {
+                // Delete artifact / manifest and its corresponding hash
+                println!("Deleting file {}[.sha256]", canonicalized.display());
+                remove_file(&canonicalized).unwrap();
+                if let Ok(path) = file.path().strip_prefix(mirror_path) {
+                    deleted.push(path.to_string_lossy().into_owned());
+                }
+                // Ignore error if the hash is not deleted (e.g. there is no hash present)
+                let mut canonicalized = canonicalized;
+                canonicalized.set_file_name((fname + ".sha256").as_ref());
+                let _ = remove_file(canonicalized);
+            } else {
+                perserve_dir = true;
+            }
+        }
+
+        if !perserve_dir {
+            println!(
+                "No useful file left in dir {}, removing the entire directory.",
+                date_dir.display()
+            );
+            remove_dir_all(&date_dir).unwrap();
+        }
+    }
+    deleted
+}
+
+/// Garbage collect, then delete the oldest dated manifests that are neither pinned
+/// nor current until the mirror takes at most `max_usage` bytes.
+/// Everything left after garbage collection is kept by the policy, so this goes beyond it
+pub fn prune(
+    mirror_path: &str,
+    mirror_url: &str,
+    policy: &RetentionPolicy,
+    max_usage: u64,
+) -> Result<(), Error> {
+    let dist = Path::new(mirror_path).join("dist");
+    if !dist.exists() {
+        // Nothing mirrored yet
+        return Ok(());
+    }
+    collect(mirror_path, mirror_url, policy);
+
+    // Retention is overridden from now on, but pins and protected dirs are not
+    let keep_all = RetentionPolicy {
+        cutoff: None,
+        keep_nightlies: None,
+        pinned: policy.pinned.clone(),
+        protected_dirs: policy.protected_dirs.clone(),
+        keep_rustup_versions: None,
+        unfinished: policy.unfinished.clone(),
+    };
+
+    loop {
+        let used = dir_size(Path::new(mirror_path));
+        if used <= max_usage {
+            return Ok(());
+        }
+
+        let (_, current_dates, _) = scan_current(&dist, &keep_all);
+        let mut candidate = None;
+        'outer: for (date, date_dir) in scan_date_dirs(&dist, &keep_all) {
+            let mut files: Vec<PathBuf> = read_dir(&date_dir)
+                .expect("inner dir")
+                .map(|file| file.unwrap().path())
+                .collect();
+            files.sort();
+            for file in files {
+                let fname = file.file_name().unwrap().to_string_lossy().to_string();
+                let channel = match manifest_channel(&fname) {
+                    Some(channel) => channel,
+                    None => continue,
+                };
+                let pinned = keep_all.pinned.contains(&Toolchain::Dated {
+                    channel: channel.to_string(),
+                    date,
+                });
+                let current = current_dates.get(&fname) == Some(&date.to_string());
+                if !pinned && !current {
+                    candidate = Some(file);
+                    break 'outer;
+                }
+            }
+        }
+
+        let manifest = candidate.ok_or_else(|| {
+            anyhow!(
+                "Mirror takes {} bytes and nothing is left to prune, at most {} bytes allowed",
+                used,
+                max_usage
+            )
+        })?;
+        println!(
+            "Pruning {} beyond the retention policy to free disk space",
+            manifest.display()
+        );
+        remove_file(&manifest)?;
+        let mut hash = manifest.into_os_string();
+        hash.push(".sha256");
+        let _ = remove_file(hash);
+        collect(mirror_path, mirror_url, &keep_all);
+    }
+}
+
+/// Delete the manifests of a url variant root whose counterpart is gone from the mirror
+pub fn collect_variant(mirror_path: &str, variant_root: &str) {
+    let dist = Path::new(variant_root).join("dist");
+    if !dist.exists() {
+        return;
+    }
+    for date_dir in read_dir(&dist).expect("Unable to read dist dir") {
+        let date_dir = date_dir.unwrap();
+        if !date_dir.file_type().unwrap().is_dir() {
+            continue;
+        }
+        let date = date_dir.file_name();
+        let mirror_dir = Path::new(mirror_path).join("dist").join(&date);
+        if mirror_dir.is_dir() {
+            for file in read_dir(date_dir.path()).expect("inner dir") {
+                let file = file.unwrap();
+                if !mirror_dir.join(file.file_name()).exists() {
+                    println!("Deleting file {}", file.path().display());
+                    let _ = remove_file(file.path());
+                }
+            }
+        }
+        if !mirror_dir.is_dir() || read_dir(date_dir.path()).unwrap().next().is_none() {
+            println!(
+                "No useful file left in dir {}, removing the entire directory.",
+                date_dir.path().display()
+            );
+            remove_dir_all(date_dir.path()).unwrap();
+        }
+    }
+}
+
+fn parse_version(version: &str) -> Option<Vec<u64>> {
+    version.split('.').map(|part| part.parse().ok()).collect()
+}
+
+/// Delete the oldest rustup versions under rustup/archive/, never the current one.
+/// Returns the versions left, newest first, with the targets they are available for
+pub fn collect_rustup(mirror_path: &str, policy: &RetentionPolicy) -> Vec<(String, Vec<String>)> {
+    let rustup = Path::new(mirror_path).join("rustup");
+    let current = read_manifest(&rustup.join("release-stable.toml"))
+        .and_then(|value| value.get("version")?.as_str().map(String::from));
+    let archive = rustup.join("archive");
+    let entries = match read_dir(&archive) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut versions = Vec::new();
+    for entry in entries {
+        let entry = entry.unwrap();
+        let name = entry.file_name().to_string_lossy().to_string();
+        match parse_version(&name) {
+            Some(parsed) if entry.file_type().unwrap().is_dir() => {
+                versions.push((parsed, name, entry.path()))
+            }
+            _ => println!("Unknown directory {}, skipping", entry.path().display()),
+        }
+    }
+    versions.sort_unstable_by(|a, b| b.0.cmp(&a.0));
+
+    let mut kept = Vec::new();
+    for (i, (_, version, dir)) in versions.into_iter().enumerate() {
+        let is_current = current.as_deref() == Some(version.as_str());
+        if policy.keep_rustup_versions.is_some_and(|keep| i >= keep) && !is_current {
+            println!("Deleting rustup {} in {}", version, dir.display());
+            remove_dir_all(&dir).unwrap();
+            continue;
+        }
+        let mut targets: Vec<String> = read_dir(&dir)
+            .expect("inner dir")
+            .map(|target| target.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        targets.sort();
+        kept.push((version, targets));
+    }
+    kept
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_sha256;
+    use std::fs::{File, create_dir_all};
+    use std::io::Write;
+
+    const MIRROR_URL: &str = "http://127.0.0.1:8000";
+
+    fn date(date: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(date, "%Y-%m-%d").unwrap()
+    }
+
+    fn temp_mirror(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!(
+            "rustup-mirror-test-gc-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = remove_dir_all(&root);
+        create_dir_all(root.join("dist")).unwrap();
+        root
+    }
+
+    fn write(path: &Path, data: &str) {
+        create_dir_all(path.parent().unwrap()).unwrap();
+        File::create(path)
+            .unwrap()
+            .write_all(data.as_bytes())
+            .unwrap();
+    }
+
+    /// Write a manifest of `date` to `name` referencing `artifacts`, and the artifacts
+    fn fake_manifest(mirror: &Path, name: &str, date: &str, artifacts: &[&str]) {
+        let mut manifest = format!("manifest-version = \"2\"\ndate = \"{}\"\n", date);
+        for (i, artifact) in artifacts.iter().enumerate() {
+            let path = mirror.join(artifact);
+            write(&path, artifact);
+            write(
+                &mirror.join(format!("{}.sha256", artifact)),
+                &file_sha256(&path).unwrap(),
+            );
+            manifest.push_str(&format!(
+                "[pkg.pkg{}.target.x86_64-unknown-linux-gnu]\navailable = true\nurl = \"{}/{}\"\nhash = \"{}\"\n",
+                i,
+                MIRROR_URL,
+                artifact,
+                file_sha256(&path).unwrap()
+            ));
+        }
+        write(&mirror.join(name), &manifest);
+        write(&mirror.join(format!("{}.sha256", name)), "");
+    }
+
+    fn collect_in(mirror: &Path, policy: &RetentionPolicy) -> Vec<String> {
+        let mut deleted = collect(mirror.to_str().unwrap(), MIRROR_URL, policy);
+        deleted.sort();
+        deleted
+    }
+
+    #[test]
+    fn retained_unions_keep_and_cutoff_with_pins() {
+        let dates: Vec<NaiveDate> = (1..=5)
+            .map(|day| date(&format!("2024-03-0{}", day)))
+            .collect();
+
+        let mut policy = RetentionPolicy::new(date("2024-03-06"), Some(2), Some(1));
+        policy.pinned.insert("nightly-2024-03-01".parse().unwrap());
+        let retained = policy.retained(&dates).unwrap();
+        let expected: HashSet<NaiveDate> = ["2024-03-01", "2024-03-04", "2024-03-05"]
+            .iter()
+            .map(|d| date(d))
+            .collect();
+        assert_eq!(retained, expected);
+
+        // Keeping more than the cutoff allows wins
+        let policy = RetentionPolicy::new(date("2024-03-06"), Some(1), Some(3));
+        assert_eq!(policy.retained(&dates).unwrap().len(), 3);
+
+        // Without any policy everything is kept
+        let policy = RetentionPolicy::new(date("2024-03-06"), None, None);
+        assert!(policy.retained(&dates).is_none());
+    }
+
+    #[test]
+    fn collect_keeps_what_retained_manifests_reference() {
+        let mirror = temp_mirror("collect");
+        for day in 1..=5 {
+            let day = format!("2024-03-0{}", day);
+            fake_manifest(
+                &mirror,
+                &format!("dist/{}/channel-rust-nightly.toml", day),
+                &day,
+                &[&format!("dist/{}/rustc-nightly.tar.gz", day)],
+            );
+        }
+        // The current nightly still ships a cargo built on 03-02
+        fake_manifest(
+            &mirror,
+            "dist/channel-rust-nightly.toml",
+            "2024-03-05",
+            &[
+                "dist/2024-03-05/rustc-nightly.tar.gz",
+                "dist/2024-03-02/cargo-nightly.tar.gz",
+            ],
+        );
+        // Only the dated copy of the current stable survives
+        fake_manifest(
+            &mirror,
+            "dist/channel-rust-stable.toml",
+            "2024-02-08",
+            &["dist/2024-02-08/rustc-1.76.0.tar.gz"],
+        );
+        fake_manifest(
+            &mirror,
+            "dist/2024-02-08/channel-rust-stable.toml",
+            "2024-02-08",
+            &["dist/2024-02-08/rustc-1.76.0.tar.gz"],
+        );
+        fake_manifest(
+            &mirror,
+            "dist/2023-12-28/channel-rust-stable.toml",
+            "2023-12-28",
+            &["dist/2023-12-28/rustc-1.75.0.tar.gz"],
+        );
+        // A numbered release is only kept while pinned
+        fake_manifest(
+            &mirror,
+            "dist/channel-rust-1.74.1.toml",
+            "2023-12-07",
+            &["dist/2023-12-07/rustc-1.74.1.tar.gz"],
+        );
+        fake_manifest(
+            &mirror,
+            "dist/channel-rust-1.74.0.toml",
+            "2023-11-16",
+            &["dist/2023-11-16/rustc-1.74.0.tar.gz"],
+        );
+
+        let mut policy = RetentionPolicy::new(date("2024-03-06"), Some(2), Some(1));
+        policy.pinned.insert("nightly-2024-03-01".parse().unwrap());
+        policy.pinned.insert("1.74.1".parse().unwrap());
+        let deleted = collect_in(&mirror, &policy);
+
+        assert_eq!(
+            deleted,
+            [
+                "dist/2023-11-16/rustc-1.74.0.tar.gz",
+                "dist/2023-12-28/channel-rust-stable.toml",
+                "dist/2023-12-28/rustc-1.75.0.tar.gz",
+                "dist/2024-03-02/channel-rust-nightly.toml",
+                "dist/2024-03-02/rustc-nightly.tar.gz",
+                "dist/2024-03-03/channel-rust-nightly.toml",
+                "dist/2024-03-03/rustc-nightly.tar.gz",
+                "dist/channel-rust-1.74.0.toml",
+            ]
+        );
+        for kept in [
+            "dist/channel-rust-nightly.toml",
+            "dist/channel-rust-stable.toml",
+            "dist/channel-rust-1.74.1.toml",
+            "dist/2024-03-01/channel-rust-nightly.toml",
+            "dist/2024-03-01/rustc-nightly.tar.gz",
+            "dist/2024-03-02/cargo-nightly.tar.gz",
+            "dist/2024-03-02/cargo-nightly.tar.gz.sha256",
+            "dist/2024-03-04/channel-rust-nightly.toml",
+            "dist/2024-03-05/channel-rust-nightly.toml",
+            "dist/2024-02-08/channel-rust-stable.toml",
+            "dist/2024-02-08/rustc-1.76.0.tar.gz",
+            "dist/2023-12-07/rustc-1.74.1.tar.gz",
+        ] {
+            assert!(mirror.join(kept).exists(), "{} was deleted", kept);
+        }
+        for gone in [
+            "dist/2024-03-02/rustc-nightly.tar.gz.sha256",
+            "dist/2024-03-03",
+            "dist/2023-12-28",
+            "dist/2023-11-16",
+        ] {
+            assert!(!mirror.join(gone).exists(), "{} was kept", gone);
+        }
+
+        remove_dir_all(&mirror).unwrap();
+    }
+
+    #[test]
+    fn prune_deletes_oldest_unpinned_manifests_first() {
+        let mirror = temp_mirror("prune");
+        for day in 1..=4 {
+            let day = format!("2024-03-0{}", day);
+            fake_manifest(
+                &mirror,
+                &format!("dist/{}/channel-rust-nightly.toml", day),
+                &day,
+                &[&format!("dist/{}/rustc-nightly.tar.gz", day)],
+            );
+        }
+        fake_manifest(
+            &mirror,
+            "dist/channel-rust-nightly.toml",
+            "2024-03-04",
+            &["dist/2024-03-04/rustc-nightly.tar.gz"],
+        );
+        let mut policy = RetentionPolicy::new(date("2024-03-05"), None, None);
+        policy.pinned.insert("nightly-2024-03-01".parse().unwrap());
+        let mirror_path = mirror.to_str().unwrap();
+
+        // A single byte over the limit only costs the oldest unpinned nightly
+        let used = dir_size(&mirror);
+        prune(mirror_path, MIRROR_URL, &policy, used - 1).unwrap();
+        assert!(!mirror.join("dist/2024-03-02").exists());
+        for kept in ["2024-03-01", "2024-03-03", "2024-03-04"] {
+            let manifest = format!("dist/{}/channel-rust-nightly.toml", kept);
+            assert!(mirror.join(manifest).exists(), "{} was pruned", kept);
+        }
+
+        // Pinned and current manifests are never pruned
+        let e = prune(mirror_path, MIRROR_URL, &policy, 0).unwrap_err();
+        assert!(e.to_string().contains("nothing is left to prune"));
+        assert!(!mirror.join("dist/2024-03-03").exists());
+        for kept in [
+            "dist/channel-rust-nightly.toml",
+            "dist/2024-03-01/channel-rust-nightly.toml",
+            "dist/2024-03-01/rustc-nightly.tar.gz",
+            "dist/2024-03-04/channel-rust-nightly.toml",
+            "dist/2024-03-04/rustc-nightly.tar.gz",
+        ] {
+            assert!(mirror.join(kept).exists(), "{} was pruned", kept);
+        }
+
+        remove_dir_all(&mirror).unwrap();
+    }
+
+    #[test]
+    fn collect_skips_unknown_and_protected_dirs() {
+        let mirror = temp_mirror("unknown");
+        fake_manifest(
+            &mirror,
+            "dist/channel-rust-nightly.toml",
+            "2024-03-05",
+            &["dist/2024-03-05/rustc-nightly.tar.gz"],
+        );
+        fake_manifest(
+            &mirror,
+            "dist/2024-03-05/channel-rust-nightly.toml",
+            "2024-03-05",
+            &["dist/2024-03-05/rustc-nightly.tar.gz"],
+        );
+        write(&mirror.join("dist/tmp/upload.tar.gz"), "upload");
+        write(&mirror.join("dist/2024-03-01/subdir/file"), "file");
+        write(&mirror.join("dist/2024-03-01/stray.tar.gz"), "stray");
+        fake_manifest(
+            &mirror,
+            "dist/2024-02-01/channel-rust-nightly.toml",
+            "2024-02-01",
+            &["dist/2024-02-01/rustc-nightly.tar.gz"],
+        );
+
+        let mut policy = RetentionPolicy::new(date("2024-03-06"), None, Some(1));
+        policy.protected_dirs.insert("2024-02-01".to_string());
+        let deleted = collect_in(&mirror, &policy);
+
+        // The stray file goes, the unknown entries keep their date dir alive
+        assert_eq!(deleted, ["dist/2024-03-01/stray.tar.gz"]);
+        assert!(mirror.join("dist/tmp/upload.tar.gz").exists());
+        assert!(mirror.join("dist/2024-03-01/subdir/file").exists());
+        assert!(
+            mirror
+                .join("dist/2024-02-01/channel-rust-nightly.toml")
+                .exists()
+        );
+        assert!(mirror.join("dist/2024-02-01/rustc-nightly.tar.gz").exists());
+
+        remove_dir_all(&mirror).unwrap();
+    }
+}
@@ -0,0 +1,557 @@
+use crate::bundle::{INDEX_FILE, Index};
+use crate::{
+    RELEASE_CHANNELS, file_sha256, lock, mirrored_artifact_hashes, read_manifest, rebase_urls,
+    rewrite_installer, write_manifest,
+};
+use anyhow::{Error, anyhow};
+use clap::Args;
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, copy, create_dir_all, metadata, read_to_string, remove_dir_all, rename};
+use std::io::Write;
+use std::path::{Component, Path};
+use std::time::Duration;
+use toml::Value;
+
+/// Where tarballs are unpacked, inside the mirror so files can be moved in place
+const STAGING_DIR: &str = ".rustup-mirror-import";
+
+/// Records which bundles were imported into the mirror
+const STATE_FILE: &str = ".rustup-mirror-import.toml";
+
+#[derive(Args)]
+pub struct ImportArgs {
+    /// Where to store mirror files
+    #[arg(short, long, default_value = "./mirror")]
+    mirror: String,
+
+    /// Where mirror is served
+    #[arg(short, long, default_value = "http://127.0.0.1:8000")]
+    url: String,
+
+    /// Wait at most this many seconds for another run on the same mirror to finish
+    #[arg(long)]
+    wait_lock: Option<u64>,
+
+    /// Point the default RUSTUP_UPDATE_ROOT of the imported rustup-init.sh at --url
+    #[arg(long)]
+    rewrite_installer: bool,
+
+    /// Bundle written by export, either a tarball or a directory
+    bundle: String,
+}
+
+/// Whether a path from the bundle stays inside the mirror
+fn is_contained(path: &str) -> bool {
+    !path.is_empty()
+        && Path::new(path)
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+}
+
+fn manifest_date(value: &Value) -> &str {
+    value
+        .get("date")
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+}
+
+/// Make the targets available in `imported` available in `existing` too
+fn merge_manifest(existing: &mut Value, imported: &Value) {
+    let (Some(pkgs), Some(imported_pkgs)) = (
+        existing.get_mut("pkg").and_then(Value::as_table_mut),
+        imported.get("pkg").and_then(Value::as_table),
+    ) else {
+        return;
+    };
+    for (pkg_name, imported_pkg) in imported_pkgs {
+        let pkg = pkgs
+            .entry(pkg_name.clone())
+            .or_insert_with(|| imported_pkg.clone());
+        let (Some(pkg_targets), Some(imported_targets)) = (
+            pkg.get_mut("target").and_then(Value::as_table_mut),
+            imported_pkg.get("target").and_then(Value::as_table),
+        ) else {
+            continue;
+        };
+        for (target, imported_target) in imported_targets {
+            if imported_target.get("available").and_then(Value::as_bool) != Some(true) {
+                continue;
+            }
+            let available = pkg_targets
+                .get(target)
+                .and_then(|t| t.get("available"))
+                .and_then(Value::as_bool);
+            if available != Some(true) {
+                pkg_targets.insert(target.clone(), imported_target.clone());
+            }
+        }
+    }
+}
+
+/// Copy a file next to its destination first, so that readers never see a partial file
+fn install(source: &Path, dest: &Path) -> Result<(), Error> {
+    create_dir_all(dest.parent().unwrap())?;
+    let mut tmp = dest.as_os_str().to_owned();
+    tmp.push(".import");
+    copy(source, &tmp)?;
+    rename(&tmp, dest)?;
+    Ok(())
+}
+
+/// Bundles imported so far, and the date of the newest manifest they brought
+fn read_state(mirror: &Path) -> (Vec<String>, Option<String>) {
+    let value = match read_manifest(&mirror.join(STATE_FILE)) {
+        Some(value) => value,
+        None => return (Vec::new(), None),
+    };
+    let applied = value
+        .get("applied")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(|id| id.as_str().map(String::from))
+        .collect();
+    let newest_date = value
+        .get("newest-date")
+        .and_then(Value::as_str)
+        .map(String::from);
+    (applied, newest_date)
+}
+
+fn write_state(
+    mirror: &Path,
+    applied: &[String],
+    newest_date: &Option<String>,
+) -> Result<(), Error> {
+    let mut table = toml::value::Table::new();
+    table.insert(
+        "applied".into(),
+        Value::Array(applied.iter().map(|id| Value::String(id.clone())).collect()),
+    );
+    if let Some(date) = newest_date {
+        table.insert("newest-date".into(), Value::String(date.clone()));
+    }
+    File::create(mirror.join(STATE_FILE))?.write_all(toml::to_string(&table)?.as_bytes())?;
+    Ok(())
+}
+
+fn rustup_version(root: &Path) -> Option<Vec<u64>> {
+    let value = read_manifest(&root.join("rustup/release-stable.toml"))?;
+    let version = value.get("version")?.as_str()?;
+    Some(
+        version
+            .split('.')
+            .map(|part| part.parse().unwrap_or(0))
+            .collect(),
+    )
+}
+
+fn import(args: &ImportArgs, root: &Path) -> Result<(), Error> {
+    let mirror = Path::new(&args.mirror);
+    let index = Index::parse(
+        &read_to_string(root.join(INDEX_FILE))
+            .map_err(|e| anyhow!("Unable to read bundle index: {}", e))?,
+    )?;
+    println!("Importing bundle {} created at {}", index.id, index.created);
+
+    // Deltas only apply on top of what they were exported against
+    let (mut applied, mut newest_date) = read_state(mirror);
+    if let Some(base_id) = &index.base_id
+        && !applied.contains(base_id)
+    {
+        return Err(anyhow!(
+            "Bundle {} is a delta of bundle {}, which has not been imported",
+            index.id,
+            base_id
+        ));
+    }
+    if let Some(base_date) = &index.base_date
+        && newest_date.as_ref().is_none_or(|date| date < base_date)
+    {
+        return Err(anyhow!(
+            "Bundle {} is a delta since {}, but the newest imported manifest is of {}",
+            index.id,
+            base_date,
+            newest_date.as_deref().unwrap_or("none")
+        ));
+    }
+
+    // Verify every file against the index
+    let mut hashes = HashMap::new();
+    for file in index.files.iter() {
+        if !is_contained(&file.path) {
+            return Err(anyhow!("Refusing to import /{}", file.path));
+        }
+        let path = root.join(&file.path);
+        let size = metadata(&path)
+            .map_err(|e| anyhow!("/{} is missing from the bundle: {}", file.path, e))?
+            .len();
+        if size != file.size || file_sha256(&path).as_deref() != Some(file.sha256.as_str()) {
+            return Err(anyhow!("/{} does not match the bundle index", file.path));
+        }
+        hashes.insert(file.path.as_str(), file.sha256.as_str());
+    }
+
+    // Verify that manifests only reference files in the bundle or the mirror
+    let mut manifests = Vec::new();
+    let mut manifest_files = HashSet::new();
+    for name in index.manifests.iter() {
+        if !hashes.contains_key(name.as_str()) {
+            return Err(anyhow!("Manifest /{} is not in the bundle index", name));
+        }
+        let value =
+            read_manifest(&root.join(name)).ok_or_else(|| anyhow!("Invalid manifest /{}", name))?;
+        for (file, hash) in mirrored_artifact_hashes(&value, &index.mirror_url) {
+            let present = match hashes.get(file.as_str()) {
+                Some(bundled) => *bundled == hash,
+                None => file_sha256(&mirror.join(&file)).as_deref() == Some(hash.as_str()),
+            };
+            if !present {
+                return Err(anyhow!(
+                    "/{} referenced by /{} is missing or corrupted",
+                    file,
+                    name
+                ));
+            }
+        }
+        manifest_files.insert(name.clone());
+        manifest_files.insert(format!("{}.sha256", name));
+        manifests.push((name, value));
+    }
+
+    // Never downgrade rustup
+    let update_rustup = match (rustup_version(root), rustup_version(mirror)) {
+        (Some(bundled), Some(mirrored)) => bundled > mirrored,
+        (bundled, _) => bundled.is_some(),
+    };
+
+    // Artifacts first, so that manifests never point to missing files
+    let mut imported = 0;
+    let mut skipped = 0;
+    let mut upstream_installer = false;
+    for file in index.files.iter() {
+        if manifest_files.contains(&file.path) {
+            continue;
+        }
+        if file.path.starts_with("rustup/")
+            && !file.path.starts_with("rustup/archive/")
+            && !update_rustup
+        {
+            skipped += 1;
+            continue;
+        }
+        upstream_installer |= file.path == "rustup/rustup-init.sh";
+        let dest = mirror.join(&file.path);
+        if file_sha256(&dest).as_deref() == Some(file.sha256.as_str()) {
+            skipped += 1;
+            continue;
+        }
+        install(&root.join(&file.path), &dest)?;
+        imported += 1;
+    }
+    println!(
+        "Imported {} files, {} already present or older",
+        imported, skipped
+    );
+    // Bundles carry the upstream installer, which points at the upstream
+    if args.rewrite_installer && upstream_installer {
+        rewrite_installer(&args.mirror, &args.url)
+            .map_err(|e| anyhow!("Failed to rewrite rustup-init.sh: {}", e))?;
+    }
+
+    for (name, mut value) in manifests {
+        rebase_urls(&mut value, &args.url);
+        let dest = mirror.join(name);
+        let is_pointer = Path::new(name).parent() == Some(Path::new("dist"))
+            && RELEASE_CHANNELS
+                .iter()
+                .any(|channel| *name == format!("dist/channel-rust-{}.toml", channel));
+
+        match read_manifest(&dest) {
+            None => {
+                println!("Producing /{}", name);
+                write_manifest(&dest, &value)?;
+            }
+            Some(mut existing) => {
+                let (date, existing_date) = (manifest_date(&value), manifest_date(&existing));
+                if is_pointer && date > existing_date {
+                    println!("Updating /{} to {}", name, date);
+                    write_manifest(&dest, &value)?;
+                } else if date == existing_date {
+                    println!("Merging /{}", name);
+                    merge_manifest(&mut existing, &value);
+                    write_manifest(&dest, &existing)?;
+                } else {
+                    println!("Keeping /{} of {}", name, existing_date);
+                }
+            }
+        }
+    }
+
+    if !applied.contains(&index.id) {
+        applied.push(index.id.clone());
+    }
+    newest_date = newest_date.max(index.newest_date.clone());
+    write_state(mirror, &applied, &newest_date)
+}
+
+pub fn run(args: ImportArgs) -> Result<(), Error> {
+    let _lock = lock::acquire(&args.mirror, args.wait_lock.map(Duration::from_secs))?;
+
+    let bundle = Path::new(&args.bundle);
+    if bundle.is_dir() {
+        return import(&args, bundle);
+    }
+
+    let staging = Path::new(&args.mirror).join(STAGING_DIR);
+    if staging.exists() {
+        remove_dir_all(&staging)?;
+    }
+    create_dir_all(&staging)?;
+    let res = tar::Archive::new(File::open(bundle)?)
+        .unpack(&staging)
+        .map_err(Error::from)
+        .and_then(|_| import(&args, &staging));
+    remove_dir_all(&staging)?;
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bundle::{BundleFile, Index};
+    use ring::digest;
+
+    const MIRROR_URL: &str = "http://127.0.0.1:8000";
+
+    fn temp_root(name: &str) -> std::path::PathBuf {
+        let root = std::env::temp_dir().join(format!(
+            "rustup-mirror-test-import-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = remove_dir_all(&root);
+        create_dir_all(&root).unwrap();
+        root
+    }
+
+    fn sha256(data: &str) -> String {
+        hex::encode(digest::digest(&digest::SHA256, data.as_bytes()))
+    }
+
+    /// A manifest of `date` whose artifacts, given as (target, path), contain their path
+    fn manifest(date: &str, artifacts: &[(&str, &str)]) -> String {
+        let mut manifest = format!("manifest-version = \"2\"\ndate = \"{}\"\n", date);
+        for (target, path) in artifacts {
+            manifest.push_str(&format!(
+                "[pkg.rust.target.{}]\navailable = true\nurl = \"{}/{}\"\nhash = \"{}\"\n",
+                target,
+                MIRROR_URL,
+                path,
+                sha256(path)
+            ));
+        }
+        manifest
+    }
+
+    /// Write `files` and an index listing them to a bundle directory
+    fn bundle(root: &Path, id: &str, files: &[(&str, String)]) -> Index {
+        let dir = root.join(id);
+        let mut index = Index {
+            id: id.to_string(),
+            base_id: None,
+            base_date: None,
+            newest_date: None,
+            created: "2024-03-01T00:00:00+00:00".to_string(),
+            mirror_url: MIRROR_URL.to_string(),
+            manifests: Vec::new(),
+            files: Vec::new(),
+        };
+        for (path, data) in files {
+            let file = dir.join(path);
+            create_dir_all(file.parent().unwrap()).unwrap();
+            File::create(&file)
+                .unwrap()
+                .write_all(data.as_bytes())
+                .unwrap();
+            if path.starts_with("dist/") && path.ends_with(".toml") {
+                let date = manifest_date(&data.parse().unwrap()).to_string();
+                index.newest_date = index.newest_date.max(Some(date));
+                index.manifests.push(path.to_string());
+            }
+            index.files.push(BundleFile {
+                path: path.to_string(),
+                sha256: sha256(data),
+                size: data.len() as u64,
+            });
+        }
+        write_index(root, &index);
+        index
+    }
+
+    fn write_index(root: &Path, index: &Index) {
+        File::create(root.join(&index.id).join(INDEX_FILE))
+            .unwrap()
+            .write_all(index.to_toml().as_bytes())
+            .unwrap();
+    }
+
+    fn import_in(root: &Path, id: &str) -> Result<(), Error> {
+        run(ImportArgs {
+            mirror: root.join("mirror").to_string_lossy().to_string(),
+            url: MIRROR_URL.to_string(),
+            wait_lock: None,
+            rewrite_installer: false,
+            bundle: root.join(id).to_string_lossy().to_string(),
+        })
+    }
+
+    fn mirrored_date(root: &Path, name: &str) -> String {
+        manifest_date(&read_manifest(&root.join("mirror").join(name)).unwrap()).to_string()
+    }
+
+    #[test]
+    fn import_rejects_files_not_matching_the_index() {
+        let root = temp_root("mismatch");
+        let artifact = "dist/2024-03-01/rust.tar.xz";
+        let files = [
+            (
+                "dist/channel-rust-stable.toml",
+                manifest("2024-03-01", &[("x86_64-unknown-linux-gnu", artifact)]),
+            ),
+            (artifact, artifact.to_string()),
+        ];
+        bundle(&root, "a", &files);
+        File::create(root.join("a").join(artifact))
+            .unwrap()
+            .write_all(b"dist/2024-03-01/rust.tar.gz")
+            .unwrap();
+
+        let e = import_in(&root, "a").unwrap_err();
+        assert!(e.to_string().contains("does not match the bundle index"));
+        assert!(!root.join("mirror").join(artifact).exists());
+        assert!(!root.join("mirror/dist/channel-rust-stable.toml").exists());
+        let _ = remove_dir_all(&root);
+    }
+
+    #[test]
+    fn pointer_manifest_only_moves_forward() {
+        let root = temp_root("pointer");
+        let pointer = "dist/channel-rust-stable.toml";
+        for (id, date) in [
+            ("a", "2024-03-02"),
+            ("b", "2024-03-01"),
+            ("c", "2024-03-03"),
+        ] {
+            bundle(&root, id, &[(pointer, manifest(date, &[]))]);
+        }
+
+        import_in(&root, "a").unwrap();
+        assert_eq!(mirrored_date(&root, pointer), "2024-03-02");
+        import_in(&root, "b").unwrap();
+        assert_eq!(mirrored_date(&root, pointer), "2024-03-02");
+        import_in(&root, "c").unwrap();
+        assert_eq!(mirrored_date(&root, pointer), "2024-03-03");
+        let _ = remove_dir_all(&root);
+    }
+
+    #[test]
+    fn dated_manifest_of_the_same_date_is_merged() {
+        let root = temp_root("merge");
+        let name = "dist/2024-03-01/channel-rust-stable.toml";
+        let targets = [
+            (
+                "x86_64-unknown-linux-gnu",
+                "dist/2024-03-01/rust-x86_64.tar.xz",
+            ),
+            (
+                "aarch64-unknown-linux-gnu",
+                "dist/2024-03-01/rust-aarch64.tar.xz",
+            ),
+        ];
+        for (id, exported, other) in [("a", targets[0], targets[1]), ("b", targets[1], targets[0])]
+        {
+            // As export leaves it, with the other target unavailable
+            let data = manifest("2024-03-01", &[exported, other]).replacen(
+                &format!("available = true\nurl = \"{}/{}\"", MIRROR_URL, other.1),
+                &format!("available = false\nurl = \"{}/{}\"", MIRROR_URL, other.1),
+                1,
+            );
+            bundle(
+                &root,
+                id,
+                &[(name, data), (exported.1, exported.1.to_string())],
+            );
+        }
+
+        import_in(&root, "a").unwrap();
+        import_in(&root, "b").unwrap();
+        let merged = read_manifest(&root.join("mirror").join(name)).unwrap();
+        for (target, path) in targets {
+            let pkg_target = &merged["pkg"]["rust"]["target"][target];
+            assert_eq!(pkg_target["available"].as_bool(), Some(true), "{}", target);
+            assert!(root.join("mirror").join(path).is_file());
+        }
+        let _ = remove_dir_all(&root);
+    }
+
+    #[test]
+    fn rustup_is_never_downgraded() {
+        let root = temp_root("rustup");
+        let binary = "rustup/dist/x86_64-unknown-linux-gnu/rustup-init";
+        for (id, version) in [("a", "1.27.0"), ("b", "1.26.0")] {
+            let release = format!("schema-version = \"1\"\nversion = \"{}\"\n", version);
+            bundle(
+                &root,
+                id,
+                &[
+                    ("rustup/release-stable.toml", release),
+                    (binary, format!("rustup {}", version)),
+                ],
+            );
+        }
+
+        import_in(&root, "a").unwrap();
+        import_in(&root, "b").unwrap();
+        let mirror = root.join("mirror");
+        assert_eq!(rustup_version(&mirror), Some(vec![1, 27, 0]));
+        assert_eq!(
+            read_to_string(mirror.join(binary)).unwrap(),
+            "rustup 1.27.0"
+        );
+        let _ = remove_dir_all(&root);
+    }
+
+    #[test]
+    fn delta_needs_its_base() {
+        let root = temp_root("delta");
+        let pointer = "dist/channel-rust-stable.toml";
+        bundle(&root, "base", &[(pointer, manifest("2024-03-01", &[]))]);
+        let mut delta = bundle(&root, "delta", &[(pointer, manifest("2024-03-02", &[]))]);
+        delta.base_id = Some("base".to_string());
+        write_index(&root, &delta);
+        let mut dated = bundle(&root, "dated", &[(pointer, manifest("2024-03-03", &[]))]);
+        dated.base_date = Some("2024-03-02".to_string());
+        write_index(&root, &dated);
+
+        let e = import_in(&root, "delta").unwrap_err();
+        assert!(e.to_string().contains("which has not been imported"));
+        let e = import_in(&root, "dated").unwrap_err();
+        assert!(
+            e.to_string()
+                .contains("newest imported manifest is of none")
+        );
+        assert!(!root.join("mirror").join(pointer).exists());
+
+        import_in(&root, "base").unwrap();
+        let e = import_in(&root, "dated").unwrap_err();
+        assert!(
+            e.to_string()
+                .contains("newest imported manifest is of 2024-03-01")
+        );
+        import_in(&root, "delta").unwrap();
+        import_in(&root, "dated").unwrap();
+        assert_eq!(mirrored_date(&root, pointer), "2024-03-03");
+        let _ = remove_dir_all(&root);
+    }
+}
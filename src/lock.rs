@@ -0,0 +1,96 @@
+use anyhow::{Error, anyhow};
+use std::fmt;
+use std::fs::{File, OpenOptions, TryLockError, create_dir_all, read_to_string};
+use std::io::Write;
+use std::path::Path;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+const LOCK_FILE: &str = ".rustup-mirror.lock";
+
+/// Exit code when another run holds the lock, EX_TEMPFAIL from sysexits.h
+pub const EXIT_ALREADY_RUNNING: i32 = 75;
+
+/// Another run is syncing the same mirror
+#[derive(Debug)]
+pub struct AlreadyRunning {
+    pub owner: String,
+}
+
+impl fmt::Display for AlreadyRunning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Mirror is locked by {}", self.owner)
+    }
+}
+
+impl std::error::Error for AlreadyRunning {}
+
+/// Advisory lock on a mirror directory, released on drop.
+/// The OS releases it as well when the process dies, whichever host it ran on
+pub struct MirrorLock {
+    file: File,
+}
+
+impl Drop for MirrorLock {
+    fn drop(&mut self) {
+        // The file itself stays: removing it would let a waiting run lock an unlinked copy
+        let _ = self.file.set_len(0);
+    }
+}
+
+fn hostname() -> String {
+    ["/proc/sys/kernel/hostname", "/etc/hostname"]
+        .iter()
+        .find_map(|path| read_to_string(path).ok())
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .or_else(|| std::env::var("COMPUTERNAME").ok())
+        .map(|name| name.trim().to_string())
+        .unwrap_or_else(|| String::from("unknown"))
+}
+
+fn try_acquire(path: &Path) -> Result<MirrorLock, Error> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .map_err(|e| anyhow!("Unable to open {}: {}", path.display(), e))?;
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            // The holder may be writing its pid right now
+            let content = read_to_string(path).unwrap_or_default();
+            let mut parts = content.split_whitespace();
+            let owner = match (parts.next(), parts.next()) {
+                (Some(pid), Some(host)) => format!("pid {} on host {}", pid, host),
+                _ => format!("an unknown process, see {}", path.display()),
+            };
+            return Err(AlreadyRunning { owner }.into());
+        }
+        Err(TryLockError::Error(e)) => {
+            return Err(anyhow!("Unable to lock {}: {}", path.display(), e));
+        }
+    }
+    file.set_len(0)?;
+    writeln!(file, "{} {}", std::process::id(), hostname())?;
+    Ok(MirrorLock { file })
+}
+
+/// Lock the mirror directory, waiting at most `wait` for another run to finish
+pub fn acquire(mirror_path: &str, wait: Option<Duration>) -> Result<MirrorLock, Error> {
+    create_dir_all(mirror_path)?;
+    let path = Path::new(mirror_path).join(LOCK_FILE);
+    let start = Instant::now();
+    loop {
+        match try_acquire(&path) {
+            Err(e) if e.is::<AlreadyRunning>() => match wait {
+                Some(wait) if start.elapsed() < wait => {
+                    println!("{}, waiting...", e);
+                    sleep(Duration::from_secs(5).min(wait - start.elapsed()));
+                }
+                _ => return Err(e),
+            },
+            res => return res,
+        }
+    }
+}
@@ -1,18 +1,42 @@
 #![forbid(unsafe_code)]
 
-use anyhow::{anyhow, Error};
-use chrono::{Duration, Local, NaiveDate};
-use clap::Parser;
+use anyhow::{Error, anyhow};
+use chrono::Local;
+use clap::{Parser, Subcommand};
+use config::Config;
+use download::Downloader;
 use filebuffer::FileBuffer;
-use indicatif::{ProgressBar, ProgressStyle};
+use gc::RetentionPolicy;
+use percent_encoding::{AsciiSet, CONTROLS, percent_decode_str, utf8_percent_encode};
+use quota::Quota;
+use rate::RateLimiter;
+use report::{ManifestReport, Report};
 use ring::digest;
-use std::collections::HashSet;
-use std::fs::{copy, create_dir_all, read_dir, remove_dir_all, remove_file, File};
-use std::io::{Read, Seek, Write};
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, create_dir_all};
+use std::io::{Read, Write};
 use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 use toml::Value;
+use toolchain::Toolchain;
+use upstream::Upstreams;
 use url::Url;
 
+mod bundle;
+mod config;
+mod download;
+mod export;
+mod gc;
+mod import;
+mod lock;
+mod quota;
+mod rate;
+mod report;
+mod toolchain;
+mod upstream;
+
 const RELEASE_CHANNELS: [&str; 3] = ["stable", "beta", "nightly"];
 
 // rustc --print target-list | awk '{print "    \"" $1 "\","}'
@@ -290,102 +314,82 @@ const TARGETS: [&str; 271] = [
     "xtensa-esp32s3-none-elf",
 ];
 
+// Hosts rustup-init is published for, as listed on https://rust-lang.github.io/rustup/installation/other.html
+const RUSTUP_TARGETS: [&str; 36] = [
+    "aarch64-apple-darwin",
+    "aarch64-linux-android",
+    "aarch64-pc-windows-msvc",
+    "aarch64-unknown-linux-gnu",
+    "aarch64-unknown-linux-musl",
+    "arm-linux-androideabi",
+    "arm-unknown-linux-gnueabi",
+    "arm-unknown-linux-gnueabihf",
+    "armv7-linux-androideabi",
+    "armv7-unknown-linux-gnueabihf",
+    "i686-apple-darwin",
+    "i686-linux-android",
+    "i686-pc-windows-gnu",
+    "i686-pc-windows-msvc",
+    "i686-unknown-linux-gnu",
+    "loongarch64-unknown-linux-gnu",
+    "loongarch64-unknown-linux-musl",
+    "mips-unknown-linux-gnu",
+    "mips64-unknown-linux-gnuabi64",
+    "mips64el-unknown-linux-gnuabi64",
+    "mipsel-unknown-linux-gnu",
+    "powerpc-unknown-linux-gnu",
+    "powerpc64-unknown-linux-gnu",
+    "powerpc64le-unknown-linux-gnu",
+    "powerpc64le-unknown-linux-musl",
+    "riscv64gc-unknown-linux-gnu",
+    "s390x-unknown-linux-gnu",
+    "x86_64-apple-darwin",
+    "x86_64-linux-android",
+    "x86_64-pc-windows-gnu",
+    "x86_64-pc-windows-msvc",
+    "x86_64-unknown-freebsd",
+    "x86_64-unknown-illumos",
+    "x86_64-unknown-linux-gnu",
+    "x86_64-unknown-linux-musl",
+    "x86_64-unknown-netbsd",
+];
+
 const DEFAULT_UPSTREAM_URL: &str = "https://static.rust-lang.org/";
 
-const MAX_RETRIES: i32 = 3;
+const MAX_RETRIES: u32 = 3;
+
+/// Where rustup files are downloaded to before being verified, inside the mirror
+const PARTIAL_DIR: &str = ".rustup-mirror-partial";
 
 fn file_sha256(file_path: &Path) -> Option<String> {
     let file = Path::new(file_path);
     if file.exists() {
-        let buffer = FileBuffer::open(&file).unwrap();
+        let buffer = FileBuffer::open(file).unwrap();
         Some(hex::encode(digest::digest(&digest::SHA256, &buffer)))
     } else {
         None
     }
 }
 
-fn download(upstream_url: &str, dir: &str, path: &str) -> Result<PathBuf, Error> {
-    let manifest = format!("{}{}", upstream_url, path);
-    let mut response;
-    let mirror = Path::new(dir);
-    let file_path = mirror.join(&path);
-    create_dir_all(file_path.parent().unwrap())?;
-    let mut dest = File::create(file_path)?;
-    let mut attempts = 0;
-
-    'outer: loop {
-        attempts += 1;
-        match reqwest::blocking::get(&manifest) {
-            Ok(res) => {
-                response = res;
-            }
-            Err(e) => {
-                if attempts >= MAX_RETRIES {
-                    return Err(anyhow!(
-                        "Failed to download after {} attempts: {}",
-                        MAX_RETRIES,
-                        e.to_string()
-                    ));
-                }
-                println!("Attempt {} failed: {}. Retrying...", attempts, e);
-                continue 'outer;
-            }
-        }
-
-        println!("File /{} downloading", path);
-        let length = match response.content_length() {
-            None => return Err(anyhow!("Not found")),
-            Some(l) => l,
-        };
-        let pb = ProgressBar::new(length);
-        pb.set_style(ProgressStyle::default_bar()
-        .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} (ETA {eta_precise})")?
-        .progress_chars("#>-"));
-
-        let mut buffer = [0u8; 4096];
-        let mut read = 0;
-
-        while read < length {
-            match response.read(&mut buffer) {
-                Ok(len) => {
-                    dest.write_all(&buffer[..len])?;
-                    read += len as u64;
-                    pb.set_position(read);
-                }
-                Err(e) => {
-                    if attempts >= MAX_RETRIES {
-                        return Err(anyhow!(
-                            "Failed to read response after {} attempts: {}",
-                            MAX_RETRIES,
-                            e.to_string()
-                        ));
-                    }
-                    println!(
-                        "Attempt {} to read response failed: {}. Retrying...",
-                        attempts, e
-                    );
-                    dest.rewind()?;
-                    pb.finish_and_clear();
-                    continue 'outer;
-                }
-            }
-        }
-
-        pb.finish_and_clear();
-        println!("File /{} downloaded", path);
-        break;
-    }
-
-    return Ok(mirror.join(path));
+#[derive(Subcommand)]
+enum Command {
+    /// Export toolchains from the mirror into a bundle for an air-gapped mirror
+    Export(export::ExportArgs),
+    /// Import a bundle written by export into the mirror
+    Import(import::ImportArgs),
 }
 
 #[derive(Parser)]
 #[command(
     version,
     about = "Make a mirror for rustup",
-    author = "Jiajie Chen <c@jia.je>"
+    author = "Jiajie Chen <c@jia.je>",
+    args_conflicts_with_subcommands = true
 )]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Where to store original manifest
     #[arg(short, long, default_value = "./orig")]
     orig: String,
@@ -402,6 +406,72 @@ struct Cli {
     #[arg(short, long)]
     gc: Option<i64>,
 
+    /// Always keep at least this many of the newest nightly toolchains, e.g. 30
+    #[arg(long)]
+    keep_nightlies: Option<usize>,
+
+    /// Keep this many of the newest rustup versions under rustup/archive/, e.g. 5
+    #[arg(long)]
+    keep_rustup_versions: Option<usize>,
+
+    /// Toolchains to always mirror and never garbage collect, e.g. nightly-2024-03-01,1.74.1
+    #[arg(long, value_delimiter = ',')]
+    pin: Vec<Toolchain>,
+
+    /// File listing toolchains to pin, one per line
+    #[arg(long)]
+    pin_file: Option<String>,
+
+    /// Directories under dist/ never to garbage collect, e.g. tmp,lost+found
+    #[arg(long, value_delimiter = ',')]
+    gc_keep: Vec<String>,
+
+    /// Maximum disk space of the mirror, pruning old toolchains to fit, e.g. 200G.
+    /// When garbage collection is not enough, the oldest toolchains are pruned even if
+    /// --gc or --keep-nightlies would keep them, but never pinned or current ones
+    #[arg(long, value_parser = quota::parse_size)]
+    max_size: Option<u64>,
+
+    /// Wait at most this many seconds for another run on the same mirror to finish
+    #[arg(long)]
+    wait_lock: Option<u64>,
+
+    /// Config file with rate limits, see README
+    #[arg(long)]
+    config: Option<String>,
+
+    /// Maximum download rate in bytes per second, e.g. 20M
+    #[arg(long, value_parser = quota::parse_size)]
+    limit_rate: Option<u64>,
+
+    /// Proxy for all upstream requests, e.g. http://proxy:3128 or socks5://proxy:1080,
+    /// instead of the HTTP(S)_PROXY environment variables. NO_PROXY still applies
+    /// unless --no-proxy is given
+    #[arg(long)]
+    proxy: Option<String>,
+
+    /// Hosts reached without --proxy or the proxies of the environment, e.g. localhost,.internal.
+    /// Replaces NO_PROXY, which is used when neither this nor the config file sets no-proxy
+    #[arg(long)]
+    no_proxy: Option<String>,
+
+    /// PEM file with extra CA certificates to trust, e.g. of a TLS-inspecting proxy
+    #[arg(long)]
+    ca_cert: Option<String>,
+
+    /// PEM file with a client certificate to present to upstreams
+    #[arg(long, requires = "client_key")]
+    client_cert: Option<String>,
+
+    /// PEM file with the PKCS#8 private key of --client-cert
+    #[arg(long, requires = "client_cert")]
+    client_key: Option<String>,
+
+    /// Point the default RUSTUP_UPDATE_ROOT of the mirrored rustup-init.sh at --url,
+    /// keeping the upstream script under --orig for export
+    #[arg(long)]
+    rewrite_installer: bool,
+
     /// Which release channel(s) to mirror, e.g. stable,nightly
     #[arg(short, long, value_delimiter = ',', default_values_t = RELEASE_CHANNELS.map(String::from))]
     channels: Vec<String>,
@@ -410,316 +480,1198 @@ struct Cli {
     #[arg(short, long, value_delimiter = ',', default_values_t = TARGETS.map(String::from))]
     targets: Vec<String>,
 
-    /// Upstream url to sync from
-    #[arg(short = 'U', long, default_value_t = DEFAULT_UPSTREAM_URL.to_string())]
-    upstream_url: String,
+    /// Which targets to mirror rustup-init for, defaults to the rustup hosts among --targets
+    #[arg(long, value_delimiter = ',')]
+    rustup_targets: Vec<String>,
+
+    /// Upstream url(s) to sync from, tried in order for each file
+    #[arg(short = 'U', long, value_delimiter = ',', default_values_t = [DEFAULT_UPSTREAM_URL.to_string()])]
+    upstream_url: Vec<String>,
+
+    /// Upstream url to verify manifests against, defaults to the first upstream url
+    #[arg(long)]
+    trusted_upstream_url: Option<String>,
+
+    /// Hardlink files from file:// upstreams instead of copying them
+    #[arg(long)]
+    hardlink: bool,
+
+    /// Give up connecting to an upstream after this many seconds
+    #[arg(long, default_value_t = 30)]
+    connect_timeout: u64,
+
+    /// Give up on a request when no data arrives for this many seconds
+    #[arg(long, default_value_t = 60)]
+    read_timeout: u64,
+
+    /// Abort downloads slower than this many bytes per second, e.g. 10K
+    #[arg(long, value_parser = quota::parse_size)]
+    low_speed_limit: Option<u64>,
+
+    /// Duration in seconds over which --low-speed-limit is measured
+    #[arg(long, default_value_t = 30)]
+    low_speed_time: u64,
+
+    /// How many times a download is attempted before giving up
+    #[arg(long, default_value_t = MAX_RETRIES)]
+    retries: u32,
+
+    /// Seconds to wait before the first retry, doubled for each of the next ones
+    #[arg(long, default_value = "1", value_parser = download::parse_delay)]
+    retry_delay: Duration,
+
+    /// Hosts manifests may point artifacts at, besides those of the upstream urls
+    /// and static.rust-lang.org
+    #[arg(long, value_delimiter = ',')]
+    allowed_hosts: Vec<String>,
+
+    /// Keep upstream urls in manifests, for clients resolving them with RUSTUP_DIST_SERVER
+    #[arg(long)]
+    keep_upstream_urls: bool,
+
+    /// Also write manifests for another url the mirror is served at into another directory,
+    /// e.g. https://mirror.example=./mirror-https
+    #[arg(long)]
+    url_variant: Vec<UrlVariant>,
+
+    /// Write a JSON summary of the run to this file
+    #[arg(long)]
+    report: Option<String>,
 }
 
-fn main() {
-    let args = Cli::parse();
+/// State shared by all manifests synced in one run
+struct Context<'a> {
+    upstreams: &'a Upstreams,
+    /// Where manifest checksums are fetched from
+    trusted_url: &'a str,
+    orig_path: &'a str,
+    mirror_path: &'a str,
+    mirror_url: &'a str,
+    filter_targets: HashSet<&'a String>,
+    /// Hosts artifact urls of manifests may point at
+    allowed_hosts: HashSet<String>,
+    /// Disk space left for the mirror, if limited
+    quota: Option<Quota>,
+    /// Keep upstream urls in manifests instead of pointing them at the mirror
+    keep_upstream_urls: bool,
+    /// Other urls the mirror is served at, each with its own manifests
+    url_variants: &'a [UrlVariant],
+}
 
-    let orig_path = &args.orig;
-    let mirror_path = &args.mirror;
-    let mirror_url = &args.url;
-    let upstream_url = &args.upstream_url;
+/// Manifests pointing at another url the mirror is served at, written to their own root
+#[derive(Clone)]
+struct UrlVariant {
+    url: String,
+    root: String,
+}
 
-    let parsed_gc_days = args.gc.map(|parsed_days| {
-        let mut day = Local::now().date_naive();
-        day -= Duration::days(parsed_days);
-        println!("Nightly before {} will be deleted", day);
-        day
-    });
+impl FromStr for UrlVariant {
+    type Err = String;
 
-    let channels = args.channels;
-    let filter_targets = args
-        .targets
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (url, root) = s
+            .rsplit_once('=')
+            .ok_or_else(|| format!("Expected URL=DIR, got {}", s))?;
+        if Url::parse(url).is_err() {
+            return Err(format!("Invalid url {}", url));
+        }
+        Ok(UrlVariant {
+            url: url.to_string(),
+            root: root.to_string(),
+        })
+    }
+}
+
+/// Characters escaped in a path segment of an url
+const PATH_SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'+')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+/// Decode a percent-encoded path relative to the mirror root,
+/// rejecting paths which would escape it
+fn decode_path(path: &str) -> Result<String, Error> {
+    let decoded = percent_decode_str(path.trim_start_matches('/'))
+        .decode_utf8()
+        .map_err(|_| anyhow!("Invalid path {}", path))?;
+    let contained = !decoded.is_empty()
+        && !decoded.contains('\\')
+        && Path::new(decoded.as_ref())
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)));
+    if !contained {
+        return Err(anyhow!("Path {} escapes the mirror", path));
+    }
+    Ok(decoded.into_owned())
+}
+
+/// Path of the file an artifact url points to, relative to the mirror root
+fn artifact_path(url: &str) -> Result<String, Error> {
+    let parsed = Url::parse(url).map_err(|e| anyhow!("Invalid url {}: {}", url, e))?;
+    decode_path(parsed.path())
+}
+
+/// Url of a file relative to the mirror root, as served at `base_url`
+fn artifact_url(base_url: &str, path: &str) -> String {
+    format!("{}/{}", base_url.trim_end_matches('/'), encode_path(path))
+}
+
+/// Percent-encode a path relative to the mirror root
+fn encode_path(path: &str) -> String {
+    path.split('/')
+        .map(|segment| utf8_percent_encode(segment, PATH_SEGMENT).to_string())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// List the files referenced by a manifest rewritten by us, relative to the mirror root,
+/// along with their expected sha256
+fn mirrored_artifact_hashes(value: &Value, mirror_url: &str) -> Vec<(String, String)> {
+    let mut artifacts = Vec::new();
+    let pkgs = match value.get("pkg").and_then(Value::as_table) {
+        Some(pkgs) => pkgs,
+        None => return artifacts,
+    };
+    for pkg in pkgs.values() {
+        let pkg_targets = match pkg.get("target").and_then(Value::as_table) {
+            Some(pkg_targets) => pkg_targets,
+            None => continue,
+        };
+        for pkg_target in pkg_targets.values() {
+            if pkg_target.get("available").and_then(Value::as_bool) != Some(true) {
+                continue;
+            }
+            for prefix in ["", "xz_"] {
+                let url = match pkg_target
+                    .get(format!("{}url", prefix))
+                    .and_then(Value::as_str)
+                {
+                    Some(url) => url,
+                    None => continue,
+                };
+                let file_name = match url.strip_prefix(mirror_url) {
+                    Some(file_name) => decode_path(file_name),
+                    None => artifact_path(url),
+                };
+                let file_name = match file_name {
+                    Ok(file_name) => file_name,
+                    Err(_) => continue,
+                };
+                let hash = pkg_target
+                    .get(format!("{}hash", prefix))
+                    .and_then(Value::as_str)
+                    .unwrap_or_default();
+                artifacts.push((file_name, hash.to_string()));
+            }
+        }
+    }
+    artifacts
+}
+
+/// List the files referenced by a manifest rewritten by us, relative to the mirror root
+fn mirrored_artifacts(value: &Value, mirror_url: &str) -> Vec<String> {
+    mirrored_artifact_hashes(value, mirror_url)
+        .into_iter()
+        .map(|(file, _)| file)
+        .collect()
+}
+
+/// Check whether a manifest and all of its artifacts are already in the mirror
+fn is_mirrored(ctx: &Context, name: &str) -> bool {
+    let mirror = Path::new(ctx.mirror_path);
+    let value = match read_manifest(&mirror.join(name)) {
+        Some(value) => value,
+        None => return false,
+    };
+
+    mirrored_artifacts(&value, ctx.mirror_url)
         .iter()
-        .collect::<std::collections::HashSet<_>>();
+        .all(|file| mirror.join(file).exists())
+}
 
-    let mut all_targets = HashSet::new();
+/// Fetch a manifest from upstream and verify its checksum
+fn fetch_manifest(ctx: &Context, name: &str) -> Result<Value, Error> {
+    let (file_path, _) = ctx.upstreams.download(ctx.orig_path, name)?;
+    let sha256_name = format!("{}.sha256", name);
+    let sha256_file_path =
+        ctx.upstreams
+            .downloader
+            .download(ctx.trusted_url, ctx.orig_path, &sha256_name)?;
+
+    let mut file = File::open(file_path.clone())?;
+    let mut data = String::new();
+    file.read_to_string(&mut data)?;
+
+    let mut sha256_file = File::open(sha256_file_path.clone())?;
+    let mut sha256_data = String::new();
+    sha256_file.read_to_string(&mut sha256_data)?;
+    if file_sha256(file_path.as_path()).as_deref() != sha256_data.get(..64) {
+        return Err(anyhow!("Checksum mismatch for /{}", name));
+    }
 
-    // All referenced files
-    let mut referenced = HashSet::new();
+    let value = data.parse::<Value>()?;
+    if value.get("manifest-version").and_then(Value::as_str) != Some("2") {
+        return Err(anyhow!("Unsupported manifest version in /{}", name));
+    }
+    let date = value
+        .get("date")
+        .and_then(Value::as_str)
+        .ok_or_else(|| anyhow!("No date in /{}", name))?;
+    println!("Manifest /{} date {}", name, date);
+    Ok(value)
+}
 
-    // Fetch rust components
-    for channel in channels.iter() {
-        let name = format!("dist/channel-rust-{}.toml", channel);
-        let file_path = download(upstream_url, orig_path, &name).unwrap();
-        let sha256_name = format!("dist/channel-rust-{}.toml.sha256", channel);
-        let sha256_file_path = download(upstream_url, orig_path, &sha256_name).unwrap();
-
-        let mut file = File::open(file_path.clone()).unwrap();
-        let mut data = String::new();
-        file.read_to_string(&mut data).unwrap();
-
-        let mut sha256_file = File::open(sha256_file_path.clone()).unwrap();
-        let mut sha256_data = String::new();
-        sha256_file.read_to_string(&mut sha256_data).unwrap();
-        assert_eq!(
-            file_sha256(file_path.as_path()).unwrap(),
-            &sha256_data[..64]
-        );
+/// Checksum of a file in the mirror, from its .sha256 file if present.
+/// Also tells whether the .sha256 file is missing
+fn mirrored_sha256(file: &Path, hash_file: &Path) -> (Option<String>, bool) {
+    let hash_file_cont = File::open(hash_file).ok().and_then(|mut f| {
+        let mut cont = String::new();
+        f.read_to_string(&mut cont).ok().map(|_| cont)
+    });
 
-        let mut value = data.parse::<Value>().unwrap();
-        assert_eq!(value["manifest-version"].as_str(), Some("2"));
-        println!(
-            "Channel {} date {}",
-            channel,
-            value["date"].as_str().unwrap()
-        );
+    let hash_file_missing = hash_file_cont.is_none();
+    (
+        hash_file_cont.or_else(|| file_sha256(file)),
+        hash_file_missing,
+    )
+}
 
-        let pkgs = value["pkg"].as_table_mut().unwrap();
-        let keys: Vec<String> = pkgs.keys().cloned().collect();
-        for pkg_name in keys {
-            let pkg = pkgs.get_mut(&pkg_name).unwrap().as_table_mut().unwrap();
-            let pkg_targets = pkg.get_mut("target").unwrap().as_table_mut().unwrap();
-            for (target, pkg_target) in pkg_targets {
-                let pkg_target = pkg_target.as_table_mut().unwrap();
-
-                // if we don't want to download this target
-                // set available to false and do not download
-                // but we will keep this table in the toml, which is required for newer version of
-                // rustup
-                if !(filter_targets.contains(target) || *target == "*") {
-                    *pkg_target.get_mut("available").unwrap() = toml::Value::Boolean(false);
-                    continue;
+/// List the artifacts of an upstream manifest for the mirrored targets,
+/// relative to the mirror root, along with their expected sha256
+fn targeted_artifacts(ctx: &Context, value: &Value) -> Vec<(String, String)> {
+    let mut artifacts = Vec::new();
+    for pkg in value["pkg"].as_table().unwrap().values() {
+        for (target, pkg_target) in pkg["target"].as_table().unwrap() {
+            if !(ctx.filter_targets.contains(target) || *target == "*")
+                || !pkg_target["available"].as_bool().unwrap()
+            {
+                continue;
+            }
+            for prefix in ["", "xz_"] {
+                let file_name =
+                    match artifact_path(pkg_target[&format!("{}url", prefix)].as_str().unwrap()) {
+                        Ok(file_name) => file_name,
+                        Err(_) => continue,
+                    };
+                let hash = pkg_target[&format!("{}hash", prefix)].as_str().unwrap();
+                artifacts.push((file_name, hash.to_string()));
+            }
+        }
+    }
+    artifacts
+}
+
+/// List the artifacts of an upstream manifest missing or outdated in the mirror
+fn pending_downloads(ctx: &Context, value: &Value) -> Vec<String> {
+    let mirror = Path::new(ctx.mirror_path);
+    targeted_artifacts(ctx, value)
+        .into_iter()
+        .filter(|(file_name, hash)| {
+            let file = mirror.join(file_name);
+            let hash_file = mirror.join(format!("{}.sha256", file_name));
+            mirrored_sha256(&file, &hash_file).0.as_deref() != Some(hash.as_str())
+        })
+        .map(|(file_name, _)| file_name)
+        .collect()
+}
+
+/// Point the urls of the available artifacts of an upstream manifest at `base_url`
+fn rebase_urls(value: &mut Value, base_url: &str) {
+    let pkgs = match value.get_mut("pkg").and_then(Value::as_table_mut) {
+        Some(pkgs) => pkgs,
+        None => return,
+    };
+    for (_, pkg) in pkgs.iter_mut() {
+        let pkg_targets = match pkg.get_mut("target").and_then(Value::as_table_mut) {
+            Some(pkg_targets) => pkg_targets,
+            None => continue,
+        };
+        for (_, pkg_target) in pkg_targets.iter_mut() {
+            if pkg_target.get("available").and_then(Value::as_bool) != Some(true) {
+                continue;
+            }
+            for key in ["url", "xz_url"] {
+                if let Some(url) = pkg_target.get_mut(key)
+                    && let Some(file_name) = url.as_str().and_then(|url| artifact_path(url).ok())
+                {
+                    *url = Value::String(artifact_url(base_url, &file_name));
                 }
+            }
+        }
+    }
+}
 
-                if pkg_target["available"].as_bool().unwrap() {
-                    all_targets.insert(target.clone());
-
-                    let prefixes = ["", "xz_"];
-                    for prefix in prefixes.iter() {
-                        let url =
-                            Url::parse(pkg_target[&format!("{}url", prefix)].as_str().unwrap())
-                                .unwrap();
-                        let mirror = Path::new(mirror_path);
-                        let file_name = url.path().replace("%20", " ");
-                        let file = mirror.join(&file_name[1..]);
-
-                        referenced.insert(normalize_path(&file));
-
-                        let hash_file = mirror.join(format!("{}.sha256", &file_name[1..]));
-                        let hash_file_cont =
-                            File::open(hash_file.clone()).ok().and_then(|mut f| {
-                                let mut cont = String::new();
-                                f.read_to_string(&mut cont).ok().map(|_| cont)
-                            });
-
-                        let hash_file_missing = hash_file_cont.is_none();
-                        let mut hash_file_cont =
-                            hash_file_cont.or_else(|| file_sha256(file.as_path()));
-
-                        let chksum_upstream =
-                            pkg_target[&format!("{}hash", prefix)].as_str().unwrap();
-
-                        let need_download = match hash_file_cont {
-                            Some(ref chksum) => chksum_upstream != chksum,
-                            None => true,
-                        };
-
-                        if need_download {
-                            let mut attempts = 0;
-                            loop {
-                                attempts += 1;
-                                download(upstream_url, mirror_path, &file_name[1..]).unwrap();
-                                hash_file_cont = file_sha256(file.as_path());
-                                if Some(chksum_upstream) == hash_file_cont.as_deref() {
-                                    break;
-                                }
-                                if attempts >= MAX_RETRIES {
-                                    panic!(
-                                        "Failed to pass checksum after {} attempts",
-                                        MAX_RETRIES
-                                    );
-                                }
-                                println!("Checksum attempt {} failed. Retrying...", attempts);
-                            }
-                            // assert_eq!(Some(chksum_upstream), hash_file_cont.as_deref());
-                        } else {
-                            println!("File {} already downloaded, skipping", file_name);
-                        }
+/// Read a TOML file, None if it is missing or invalid
+fn read_manifest(path: &Path) -> Option<Value> {
+    std::fs::read_to_string(path)
+        .ok()
+        .and_then(|data| data.parse::<Value>().ok())
+}
+
+/// Write a manifest and its checksum file
+fn write_manifest(path: &Path, value: &Value) -> Result<(), Error> {
+    create_dir_all(path.parent().unwrap())?;
+    File::create(path)?.write_all(toml::to_string(value)?.as_bytes())?;
+    let mut sha256_path = path.as_os_str().to_owned();
+    sha256_path.push(".sha256");
+    File::create(sha256_path)?.write_all(
+        format!(
+            "{}  {}",
+            file_sha256(path).unwrap(),
+            path.file_name().unwrap().to_string_lossy()
+        )
+        .as_bytes(),
+    )?;
+    Ok(())
+}
 
-                        if need_download || hash_file_missing {
-                            File::create(hash_file)
-                                .unwrap()
-                                .write_all(hash_file_cont.unwrap().as_bytes())
-                                .unwrap();
-                            println!("Writing checksum for file {}", file_name);
+/// Write an upstream manifest to the mirror, and one rewritten copy per url variant
+fn publish(ctx: &Context, name: &str, value: &Value) -> Result<(), Error> {
+    let mut rewritten = value.clone();
+    if !ctx.keep_upstream_urls {
+        rebase_urls(&mut rewritten, ctx.mirror_url);
+    }
+    println!("Producing /{}", name);
+    write_manifest(&Path::new(ctx.mirror_path).join(name), &rewritten)?;
+
+    for variant in ctx.url_variants {
+        let mut rewritten = value.clone();
+        rebase_urls(&mut rewritten, &variant.url);
+        println!("Producing /{} for {}", name, variant.url);
+        write_manifest(&Path::new(&variant.root).join(name), &rewritten)?;
+    }
+    Ok(())
+}
+
+/// Check that every artifact to fetch for a manifest comes from an allowed host,
+/// and stays inside the mirror
+fn check_artifact_urls(ctx: &Context, value: &Value) -> Result<(), Error> {
+    let pkgs = value
+        .get("pkg")
+        .and_then(Value::as_table)
+        .ok_or_else(|| anyhow!("No packages in manifest"))?;
+    for (pkg_name, pkg) in pkgs {
+        let pkg_targets = match pkg.get("target").and_then(Value::as_table) {
+            Some(pkg_targets) => pkg_targets,
+            None => continue,
+        };
+        for (target, pkg_target) in pkg_targets {
+            if !(ctx.filter_targets.contains(target) || *target == "*")
+                || pkg_target.get("available").and_then(Value::as_bool) != Some(true)
+            {
+                continue;
+            }
+            for key in ["url", "xz_url"] {
+                let url = pkg_target
+                    .get(key)
+                    .and_then(Value::as_str)
+                    .ok_or_else(|| anyhow!("Package {} for {} has no {}", pkg_name, target, key))?;
+                let host = Url::parse(url)?.host_str().map(str::to_lowercase);
+                if !host.is_some_and(|host| ctx.allowed_hosts.contains(&host)) {
+                    return Err(anyhow!(
+                        "Package {} for {} points at {}, which is not an allowed host",
+                        pkg_name,
+                        target,
+                        url
+                    ));
+                }
+                artifact_path(url)
+                    .map_err(|e| anyhow!("Package {} for {}: {}", pkg_name, target, e))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Download an artifact until it matches its upstream checksum, which is returned
+fn fetch_artifact(
+    ctx: &mut Context,
+    file_name: &str,
+    chksum_upstream: &str,
+    report: &mut ManifestReport,
+) -> Result<String, Error> {
+    let file = Path::new(ctx.mirror_path).join(file_name);
+    if let Some(quota) = &ctx.quota {
+        quota.check(file_name)?;
+    }
+    let replaced = file.metadata().map(|m| m.len()).unwrap_or_default();
+    let mut attempts = 0;
+    let chksum = loop {
+        attempts += 1;
+        let (_, served_by) = ctx.upstreams.download(ctx.mirror_path, file_name)?;
+        report.bytes += file.metadata().map(|m| m.len()).unwrap_or_default();
+        let chksum = file_sha256(file.as_path());
+        if let Some(chksum) = chksum.filter(|chksum| chksum == chksum_upstream) {
+            break chksum;
+        }
+        ctx.upstreams.report_failure(served_by);
+        if attempts >= ctx.upstreams.downloader.retries {
+            return Err(anyhow!(
+                "/{} failed to pass checksum after {} attempts",
+                file_name,
+                attempts
+            ));
+        }
+        println!("Checksum attempt {} failed. Retrying...", attempts);
+    };
+    if let Some(quota) = &mut ctx.quota {
+        quota.record(&file, replaced);
+    }
+    Ok(chksum)
+}
+
+/// Fetch all artifacts of a manifest, then write the rewritten manifest to the mirror.
+/// A failed artifact does not stop the others, but the manifest is not written
+fn sync_manifest(
+    ctx: &mut Context,
+    name: &str,
+    mut value: Value,
+    report: &mut ManifestReport,
+) -> Result<Value, Error> {
+    let mirror_path = ctx.mirror_path;
+
+    let pkgs = value["pkg"].as_table_mut().unwrap();
+    let keys: Vec<String> = pkgs.keys().cloned().collect();
+    for pkg_name in keys {
+        let pkg = pkgs.get_mut(&pkg_name).unwrap().as_table_mut().unwrap();
+        let pkg_targets = pkg.get_mut("target").unwrap().as_table_mut().unwrap();
+        for (target, pkg_target) in pkg_targets {
+            let pkg_target = pkg_target.as_table_mut().unwrap();
+
+            // if we don't want to download this target
+            // set available to false and do not download
+            // but we will keep this table in the toml, which is required for newer version of
+            // rustup
+            if !(ctx.filter_targets.contains(target) || *target == "*") {
+                *pkg_target.get_mut("available").unwrap() = toml::Value::Boolean(false);
+                continue;
+            }
+
+            if pkg_target["available"].as_bool().unwrap() {
+                let prefixes = ["", "xz_"];
+                for prefix in prefixes.iter() {
+                    let file_name =
+                        artifact_path(pkg_target[&format!("{}url", prefix)].as_str().unwrap())?;
+                    let mirror = Path::new(mirror_path);
+                    let file = mirror.join(&file_name);
+
+                    let hash_file = mirror.join(format!("{}.sha256", &file_name));
+                    let (mut hash_file_cont, hash_file_missing) =
+                        mirrored_sha256(&file, &hash_file);
+
+                    let chksum_upstream = pkg_target[&format!("{}hash", prefix)].as_str().unwrap();
+
+                    let need_download = match hash_file_cont {
+                        Some(ref chksum) => chksum_upstream != chksum,
+                        None => true,
+                    };
+
+                    if need_download {
+                        match fetch_artifact(ctx, &file_name, chksum_upstream, report) {
+                            Ok(chksum) => {
+                                hash_file_cont = Some(chksum);
+                                report.downloaded += 1;
+                            }
+                            Err(e) => {
+                                println!("Failed to fetch /{}: {}", file_name, e);
+                                report.failed += 1;
+                                report.errors.push(e.to_string());
+                                continue;
+                            }
                         }
+                    } else {
+                        println!("File /{} already downloaded, skipping", file_name);
+                        report.skipped += 1;
+                    }
 
-                        pkg_target.insert(
-                            format!("{}url", prefix),
-                            Value::String(format!("{}{}", mirror_url, file_name)),
-                        );
+                    if need_download || hash_file_missing {
+                        File::create(hash_file)
+                            .unwrap()
+                            .write_all(hash_file_cont.unwrap().as_bytes())
+                            .unwrap();
+                        println!("Writing checksum for file /{}", file_name);
                     }
                 }
             }
         }
+    }
 
-        let output = toml::to_string(&value).unwrap();
-        let path = Path::new(mirror_path).join(&name);
-        create_dir_all(path.parent().unwrap()).unwrap();
-        let mut file = File::create(path.clone()).unwrap();
-        println!("Producing /{}", name);
-        file.write_all(output.as_bytes()).unwrap();
-
-        let sha256_new_file = file_sha256(&path).unwrap();
-        let sha256_new_file_path = Path::new(mirror_path).join(&sha256_name);
-        let mut file = File::create(sha256_new_file_path.clone()).unwrap();
-        println!("Producing /{}", sha256_name);
-        file.write_all(format!("{}  channel-rust-{}.toml", sha256_new_file, channel).as_bytes())
-            .unwrap();
+    if report.failed > 0 {
+        return Err(anyhow!(
+            "{} of {} artifacts failed, not publishing",
+            report.failed,
+            report.artifacts
+        ));
+    }
+    publish(ctx, name, &value)?;
 
-        let date = value["date"].as_str().unwrap();
+    Ok(value)
+}
 
-        let alt_name = format!("dist/{}/channel-rust-{}.toml", date, channel);
-        let alt_path = Path::new(mirror_path).join(&alt_name);
-        create_dir_all(alt_path.parent().unwrap()).unwrap();
-        copy(path, alt_path).unwrap();
-        println!("Producing /{}", alt_name);
+/// Outcome of fetching rustup files, printed at the end of the run
+#[derive(Default)]
+struct RustupSummary {
+    updated: usize,
+    up_to_date: usize,
+    failed: Vec<(String, Error)>,
+}
 
-        let alt_sha256_new_file_name =
-            format!("dist/{}/channel-rust-{}.toml.sha256", date, channel);
-        let alt_sha256_new_file_path = Path::new(mirror_path).join(&alt_sha256_new_file_name);
-        copy(sha256_new_file_path, alt_sha256_new_file_path).unwrap();
-        println!("Producing /{}", alt_sha256_new_file_name);
+impl RustupSummary {
+    /// Tells whether the file is now in the mirror
+    fn record(&mut self, path: String, res: Result<bool, Error>) -> bool {
+        match res {
+            Ok(true) => self.updated += 1,
+            Ok(false) => self.up_to_date += 1,
+            Err(e) => {
+                println!(
+                    "Failed to fetch /{}, keeping the mirrored copy if any: {}",
+                    path, e
+                );
+                self.failed.push((path, e));
+                return false;
+            }
+        }
+        true
     }
 
-    // Fetch latest binary of rustup
-    println!("Downloading latest binary of rustup...");
-    for target in &all_targets {
-        if target == "*" {
-            continue;
+    fn print(&self) {
+        println!(
+            "Rustup files: {} updated, {} up to date, {} failed",
+            self.updated,
+            self.up_to_date,
+            self.failed.len()
+        );
+        for (path, e) in self.failed.iter() {
+            println!("  /{}: {}", path, e);
         }
+    }
 
-        let is_windows = target.contains("windows");
+    /// Summary for --report, along with the rustup versions served after garbage collection
+    fn to_json(
+        &self,
+        current: Option<&String>,
+        versions: &[(String, Vec<String>)],
+    ) -> serde_json::Value {
+        json!({
+            "updated": self.updated,
+            "up-to-date": self.up_to_date,
+            "failed": self
+                .failed
+                .iter()
+                .map(|(path, e)| json!({ "path": path, "error": e.to_string() }))
+                .collect::<Vec<_>>(),
+            "current": current,
+            "versions": versions
+                .iter()
+                .map(|(version, targets)| json!({ "version": version, "targets": targets }))
+                .collect::<Vec<_>>(),
+        })
+    }
+}
 
-        let ext = if is_windows { ".exe" } else { "" };
+/// Write the --report file if asked for
+fn write_report(path: Option<&str>, report: &Report, success: bool) -> Result<(), Error> {
+    if let Some(path) = path {
+        report
+            .write(Path::new(path), success)
+            .map_err(|e| anyhow!("Unable to write report {}: {}", path, e))?;
+    }
+    Ok(())
+}
 
-        if download(
-            upstream_url,
-            mirror_path,
-            &format!("rustup/dist/{}/rustup-init{}", target, ext),
-        )
-        .is_err()
-        {
-            println!("Failed to fetch rustup-init for target {}, ignored", target);
-        }
+/// End the run with an error, recorded in the --report file if asked for
+fn abort_run(path: Option<&str>, report: &mut Report, error: String, code: i32) -> ! {
+    eprintln!("{}", error);
+    report.errors.push(error);
+    if let Err(e) = write_report(path, report, false) {
+        eprintln!("{}", e);
     }
+    std::process::exit(code);
+}
 
-    // Fetch rustup self update
-    println!("Downloading rustup self update manifest...");
-    let self_update_manifest_path =
-        download(upstream_url, orig_path, "rustup/release-stable.toml").unwrap();
-
-    let mut self_update_manifest = File::open(self_update_manifest_path.clone()).unwrap();
-    let mut self_update_manifest_data = String::new();
-    self_update_manifest
-        .read_to_string(&mut self_update_manifest_data)
-        .unwrap();
-
-    let self_update_manifest_val = self_update_manifest_data.parse::<Value>().unwrap();
-    assert_eq!(
-        self_update_manifest_val["schema-version"].as_str(),
-        Some("1")
-    );
+/// Download a rustup file into a staging dir, verify it against its .sha256 from the trusted
+/// upstream, and only then replace the mirrored copy. Unless `checksum_required`, a file
+/// without a published .sha256 is taken unverified.
+/// Tells whether the mirrored copy was replaced
+fn fetch_rustup_file(ctx: &Context, path: &str, checksum_required: bool) -> Result<bool, Error> {
+    let downloader = &ctx.upstreams.downloader;
+    let mirror = Path::new(ctx.mirror_path);
+    let staging = mirror.join(PARTIAL_DIR);
+    let staging_dir = staging.to_str().unwrap();
+    let sha256_name = format!("{}.sha256", path);
+    let expected = match downloader.download(ctx.trusted_url, staging_dir, &sha256_name) {
+        Ok(sha256_path) => {
+            let data = std::fs::read_to_string(sha256_path)?;
+            Some(
+                data.get(..64)
+                    .ok_or_else(|| anyhow!("Invalid checksum file /{}", sha256_name))?
+                    .to_string(),
+            )
+        }
+        Err(e) if !checksum_required && e.is::<download::NotFound>() => {
+            println!("No checksum for /{}, not verified", path);
+            None
+        }
+        Err(e) => return Err(anyhow!("Unable to fetch checksum /{}: {}", sha256_name, e)),
+    };
+    let install = |name: &str| -> Result<(), Error> {
+        let dest = mirror.join(name);
+        create_dir_all(dest.parent().unwrap())?;
+        std::fs::rename(staging.join(name), dest)?;
+        Ok(())
+    };
 
-    let self_version = self_update_manifest_val["version"].as_str().unwrap();
+    if let Some(expected) = &expected
+        && file_sha256(&mirror.join(path)).as_deref() == Some(expected.as_str())
+    {
+        println!("File /{} already downloaded, skipping", path);
+        install(&sha256_name)?;
+        return Ok(false);
+    }
 
-    for target in all_targets {
-        if target == "*" {
-            continue;
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        let (file, served_by) = ctx.upstreams.download(staging_dir, path)?;
+        let verified = match &expected {
+            Some(expected) => file_sha256(&file).as_deref() == Some(expected.as_str()),
+            None => true,
+        };
+        if verified {
+            install(path)?;
+            if expected.is_some() {
+                install(&sha256_name)?;
+            }
+            return Ok(true);
+        }
+        ctx.upstreams.report_failure(served_by);
+        if attempts >= downloader.retries {
+            std::fs::remove_file(&file)?;
+            return Err(anyhow!("Download does not match its checksum"));
         }
+        println!("Checksum attempt {} failed. Retrying...", attempts);
+    }
+}
 
-        let is_windows = target.contains("windows");
+/// Version of rustup in a self update manifest, checking its schema
+fn parse_self_update_manifest(data: &str) -> Result<String, Error> {
+    let value = data.parse::<Value>()?;
+    if value.get("schema-version").and_then(Value::as_str) != Some("1") {
+        return Err(anyhow!("Unsupported schema of rustup/release-stable.toml"));
+    }
+    let version = value
+        .get("version")
+        .and_then(Value::as_str)
+        .ok_or_else(|| anyhow!("No version in rustup/release-stable.toml"))?;
+    let valid = version.split('.').count() == 3
+        && version
+            .split('.')
+            .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit()));
+    if !valid {
+        return Err(anyhow!("Invalid rustup version {}", version));
+    }
+    Ok(version.to_string())
+}
 
-        let ext = if is_windows { ".exe" } else { "" };
+/// Version of rustup the mirror serves self updates for
+fn served_rustup_version(mirror_path: &str) -> Option<String> {
+    let data =
+        std::fs::read_to_string(Path::new(mirror_path).join("rustup/release-stable.toml")).ok()?;
+    parse_self_update_manifest(&data).ok()
+}
 
-        if download(
-            upstream_url,
-            mirror_path,
-            &format!(
-                "rustup/archive/{}/{}/rustup-init{}",
-                self_version, target, ext
-            ),
-        )
-        .is_err()
-        {
-            println!("Failed to fetch rustup-init for target {}, ignored", target);
+/// Fetch rustup-init for each target, then the self update manifest and the binaries it
+/// implies. The manifest is only published once those binaries are in the mirror, so that
+/// `rustup self update` never sees a version it cannot download
+fn sync_rustup(
+    ctx: &Context,
+    targets: &[&String],
+    summary: &mut RustupSummary,
+) -> Result<(), Error> {
+    println!("Downloading latest binary of rustup...");
+    for target in targets {
+        let ext = if target.contains("windows") {
+            ".exe"
+        } else {
+            ""
+        };
+        let path = format!("rustup/dist/{}/rustup-init{}", target, ext);
+        let res = fetch_rustup_file(ctx, &path, true);
+        summary.record(path, res);
+    }
+
+    println!("Downloading rustup self update manifest...");
+    let (manifest_path, _) = ctx
+        .upstreams
+        .download(ctx.orig_path, "rustup/release-stable.toml")?;
+    let version = parse_self_update_manifest(&std::fs::read_to_string(manifest_path)?)?;
+
+    // Every target needs the binary of this version, whatever happened to rustup/dist
+    let mut missing = Vec::new();
+    for target in targets {
+        let ext = if target.contains("windows") {
+            ".exe"
+        } else {
+            ""
+        };
+        let path = format!("rustup/archive/{}/{}/rustup-init{}", version, target, ext);
+        let res = fetch_rustup_file(ctx, &path, true);
+        if !summary.record(path, res) {
+            missing.push(target.as_str());
         }
     }
+    if !missing.is_empty() {
+        return Err(anyhow!(
+            "rustup {} is missing for {}, keeping the previous self update manifest",
+            version,
+            missing.join(", ")
+        ));
+    }
 
-    copy(
-        self_update_manifest_path,
-        Path::new(mirror_path).join("rustup/release-stable.toml"),
-    )
-    .unwrap();
+    // Rewritten with only what rustup reads, then moved in place
+    let mut table = toml::value::Table::new();
+    table.insert("schema-version".into(), Value::String("1".into()));
+    table.insert("version".into(), Value::String(version.clone()));
+    let path = Path::new(ctx.mirror_path).join("rustup/release-stable.toml");
+    let tmp = Path::new(ctx.mirror_path).join("rustup/release-stable.toml.tmp");
+    create_dir_all(path.parent().unwrap())?;
+    File::create(&tmp)?.write_all(toml::to_string(&table)?.as_bytes())?;
+    std::fs::rename(tmp, path)?;
+    println!(
+        "Producing /rustup/release-stable.toml for rustup {}",
+        version
+    );
+    Ok(())
+}
 
-    // Garbage collect old nightly builds, and unreferenced stable/beta builds
-    for date_dir in read_dir(Path::new(mirror_path).join("dist")).expect("Unable to read dist dir")
-    {
-        let date_dir = date_dir.unwrap();
-        if !date_dir.file_type().unwrap().is_dir() {
-            // Is metadata
-            continue;
+/// Copy the upstream rustup-init.sh and its checksum under orig before they are rewritten,
+/// or remove a stale copy when they are not
+fn keep_upstream_installer(mirror_path: &str, orig_path: &str, keep: bool) -> Result<(), Error> {
+    let (mirror, orig) = (Path::new(mirror_path), Path::new(orig_path));
+    create_dir_all(orig.join("rustup"))?;
+    for name in ["rustup/rustup-init.sh", "rustup/rustup-init.sh.sha256"] {
+        let _ = std::fs::remove_file(orig.join(name));
+        if keep && mirror.join(name).is_file() {
+            std::fs::copy(mirror.join(name), orig.join(name))?;
         }
+    }
+    Ok(())
+}
 
-        let clear_nightly = if let Some(parsed_gc_days) = parsed_gc_days {
-            let dir_name = date_dir.file_name().into_string().unwrap();
-            let parsed_dir_name = NaiveDate::parse_from_str(&dir_name, "%Y-%m-%d").unwrap();
-            parsed_dir_name < parsed_gc_days
+/// Point the default RUSTUP_UPDATE_ROOT of the mirrored rustup-init.sh at the mirror
+fn rewrite_installer(mirror_path: &str, mirror_url: &str) -> Result<(), Error> {
+    let path = Path::new(mirror_path).join("rustup/rustup-init.sh");
+    let script = std::fs::read_to_string(&path)?;
+    let mut found = false;
+    let mut rewritten = String::new();
+    for line in script.split_inclusive('\n') {
+        if line.trim_start().starts_with("RUSTUP_UPDATE_ROOT=") {
+            found = true;
+            rewritten.push_str(&format!(
+                "RUSTUP_UPDATE_ROOT=\"${{RUSTUP_UPDATE_ROOT:-{}/rustup}}\"\n",
+                mirror_url.trim_end_matches('/')
+            ));
         } else {
-            false
+            rewritten.push_str(line);
+        }
+    }
+    if !found {
+        return Err(anyhow!("No RUSTUP_UPDATE_ROOT in the script"));
+    }
+    // Both files may be hardlinked to an upstream, so they are replaced rather than written to
+    let rustup = Path::new(mirror_path).join("rustup");
+    let tmp = rustup.join("rustup-init.sh.tmp");
+    File::create(&tmp)?.write_all(rewritten.as_bytes())?;
+    std::fs::rename(&tmp, &path)?;
+    File::create(&tmp)?
+        .write_all(format!("{}  rustup-init.sh", file_sha256(&path).unwrap()).as_bytes())?;
+    std::fs::rename(&tmp, rustup.join("rustup-init.sh.sha256"))?;
+    println!("Producing /rustup/rustup-init.sh for {}", mirror_url);
+    Ok(())
+}
+
+/// HTTP client shared by all downloads, set up from the command line and config file
+fn http_client(args: &Cli, config: &Config) -> Result<reqwest::blocking::Client, Error> {
+    let read =
+        |path: &str| std::fs::read(path).map_err(|e| anyhow!("Unable to read {}: {}", path, e));
+    let mut builder = reqwest::blocking::Client::builder()
+        .connect_timeout(Duration::from_secs(args.connect_timeout))
+        .timeout(Duration::from_secs(args.read_timeout));
+
+    let no_proxy = args.no_proxy.as_ref().or(config.no_proxy.as_ref());
+    let exceptions = || match no_proxy {
+        Some(hosts) => reqwest::NoProxy::from_string(hosts),
+        None => reqwest::NoProxy::from_env(),
+    };
+    if let Some(proxy) = args.proxy.as_ref().or(config.proxy.as_ref()) {
+        builder = builder.proxy(reqwest::Proxy::all(proxy)?.no_proxy(exceptions()));
+    } else if no_proxy.is_some() {
+        // Any proxy set here turns off those of the environment, so they are set here as well
+        let env = |name: &str| {
+            std::env::var(name)
+                .or_else(|_| std::env::var(name.to_uppercase()))
+                .ok()
+                .filter(|url| !url.is_empty())
         };
+        if let Some(proxy) = env("https_proxy") {
+            builder = builder.proxy(reqwest::Proxy::https(proxy)?.no_proxy(exceptions()));
+        }
+        if let Some(proxy) = env("http_proxy") {
+            builder = builder.proxy(reqwest::Proxy::http(proxy)?.no_proxy(exceptions()));
+        }
+        if let Some(proxy) = env("all_proxy") {
+            builder = builder.proxy(reqwest::Proxy::all(proxy)?.no_proxy(exceptions()));
+        }
+    }
+    if let Some(ca_cert) = args.ca_cert.as_ref().or(config.ca_cert.as_ref()) {
+        for cert in reqwest::Certificate::from_pem_bundle(&read(ca_cert)?)? {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+    match (
+        args.client_cert.as_ref().or(config.client_cert.as_ref()),
+        args.client_key.as_ref().or(config.client_key.as_ref()),
+    ) {
+        (Some(cert), Some(key)) => {
+            builder = builder.identity(reqwest::Identity::from_pkcs8_pem(
+                &read(cert)?,
+                &read(key)?,
+            )?);
+        }
+        (None, None) => {}
+        _ => {
+            return Err(anyhow!(
+                "A client certificate needs both a certificate and a key"
+            ));
+        }
+    }
+    Ok(builder.build()?)
+}
 
-        // Is there anyone left?
-        let mut perserve_dir = false;
+fn main() {
+    let args = Cli::parse();
 
-        for file in read_dir(date_dir.path()).expect("inner dir") {
-            let file = file.unwrap();
-            let fname = file.file_name();
-            let fname = fname.to_string_lossy();
-            if fname.ends_with(".sha256") {
-                // Is an hash, will be deleted alongside the hashed file
-                continue;
+    if let Some(command) = args.command {
+        let res = match command {
+            Command::Export(export_args) => export::run(export_args),
+            Command::Import(import_args) => import::run(import_args),
+        };
+        if let Err(e) = res {
+            eprintln!("{}", e);
+            if e.is::<lock::AlreadyRunning>() {
+                std::process::exit(lock::EXIT_ALREADY_RUNNING);
             }
+            std::process::exit(1);
+        }
+        return;
+    }
 
-            let canonicalized = file.path().canonicalize().unwrap();
-            let normalized = normalize_path(&file.path());
+    // Set up first, so that a run failing early does not leave the previous report behind
+    let report_path = args.report.as_deref();
+    let mut report = Report::new();
 
-            // Filter referenced artifacts. Manifests will never be referenced
-            let to_be_deleted = if referenced.contains(&normalized) {
-                false
-            } else if fname.find("nightly").is_some() {
-                // Is nightly artifact or manifest
-                clear_nightly
+    let config = match &args.config {
+        Some(path) => match config::read_config(Path::new(path)) {
+            Ok(config) => config,
+            Err(e) => abort_run(report_path, &mut report, e.to_string(), 1),
+        },
+        None => Config::default(),
+    };
+
+    let mut pins = args.pin.clone();
+    if let Some(pin_file) = &args.pin_file {
+        match toolchain::read_pin_file(Path::new(pin_file)) {
+            Ok(pin_file) => pins.extend(pin_file),
+            Err(e) => abort_run(report_path, &mut report, e.to_string(), 1),
+        }
+    }
+
+    let client = match http_client(&args, &config) {
+        Ok(client) => client,
+        Err(e) => abort_run(
+            report_path,
+            &mut report,
+            format!("Unable to set up HTTP client: {}", e),
+            1,
+        ),
+    };
+
+    let orig_path = &args.orig;
+    let mirror_path = &args.mirror;
+    let mirror_url = &args.url;
+    let upstreams = Upstreams::new(
+        args.upstream_url.clone(),
+        Downloader {
+            hardlink: args.hardlink,
+            client,
+            low_speed: args
+                .low_speed_limit
+                .map(|limit| (limit, Duration::from_secs(args.low_speed_time))),
+            retries: args.retries.max(1),
+            retry_delay: args.retry_delay,
+            rate: RateLimiter::new(args.limit_rate.or(config.limit_rate), config.schedules),
+        },
+    );
+    let trusted_url = args
+        .trusted_upstream_url
+        .as_deref()
+        .unwrap_or(&args.upstream_url[0]);
+
+    // Held until the end of the run. exit() skips drop, so it is dropped before exiting
+    let lock = match lock::acquire(mirror_path, args.wait_lock.map(Duration::from_secs)) {
+        Ok(lock) => lock,
+        Err(e) => {
+            let code = if e.is::<lock::AlreadyRunning>() {
+                lock::EXIT_ALREADY_RUNNING
             } else {
-                // Is stable/beta artifact or manifest, delete by default
-                true
+                1
             };
+            abort_run(report_path, &mut report, e.to_string(), code)
+        }
+    };
 
-            if to_be_deleted {
-                // Delete artifact / manifest and its corresponding hash
-                println!("Deleting file {}[.sha256]", canonicalized.display());
-                remove_file(&canonicalized).unwrap();
-                // Ignore error if the hash is not deleted (e.g. there is no hash present)
-                let mut canonicalized = canonicalized;
-                canonicalized.set_file_name((fname + ".sha256").as_ref());
-                let _ = remove_file(canonicalized);
-            } else {
-                perserve_dir = true;
+    let mut retention =
+        RetentionPolicy::new(Local::now().date_naive(), args.gc, args.keep_nightlies);
+    retention.pinned.extend(pins.iter().cloned());
+    // Numbered releases synced as channels are kept like pinned ones
+    retention.pinned.extend(
+        args.channels
+            .iter()
+            .filter_map(|channel| match channel.parse() {
+                Ok(version @ Toolchain::Version(_)) => Some(version),
+                _ => None,
+            }),
+    );
+    retention
+        .protected_dirs
+        .extend(args.gc_keep.iter().cloned());
+    retention.keep_rustup_versions = args.keep_rustup_versions;
+    if let Some(cutoff) = retention.cutoff {
+        println!("Nightly before {} will be deleted", cutoff);
+    }
+    if let Some(keep) = retention.keep_nightlies {
+        println!("At least {} nightly will be kept", keep);
+    }
+
+    let channels = args.channels;
+
+    let mut ctx = Context {
+        upstreams: &upstreams,
+        trusted_url,
+        orig_path,
+        mirror_path,
+        mirror_url,
+        filter_targets: args.targets.iter().collect(),
+        allowed_hosts: args
+            .upstream_url
+            .iter()
+            .map(String::as_str)
+            .chain([trusted_url, DEFAULT_UPSTREAM_URL])
+            .filter_map(|url| Url::parse(url).ok()?.host_str().map(str::to_lowercase))
+            .chain(args.allowed_hosts.iter().map(|host| host.to_lowercase()))
+            .collect(),
+        quota: None,
+        keep_upstream_urls: args.keep_upstream_urls,
+        url_variants: &args.url_variant,
+    };
+
+    let mut failed = false;
+
+    // Fetch manifests first, so that the space required is known before downloading
+    let mut names = Vec::new();
+    for channel in channels.iter() {
+        names.push((Some(channel), format!("dist/channel-rust-{}.toml", channel)));
+    }
+
+    // Fetch pinned toolchains unless already complete in the mirror
+    for pin in pins.iter() {
+        let name = pin.manifest_name();
+        if is_mirrored(&ctx, &name) {
+            println!("Pinned toolchain {} already mirrored", pin);
+        } else {
+            println!("Fetching pinned toolchain {}", pin);
+            names.push((None, name));
+        }
+    }
+
+    // A channel which cannot be fetched or points outside of the upstreams fails,
+    // the others are still synced
+    let mut manifests = Vec::new();
+    for (index, (channel, name)) in names.into_iter().enumerate() {
+        report
+            .manifests
+            .push(ManifestReport::new(&name, channel.map(String::as_str)));
+        let manifest_report = &mut report.manifests[index];
+        let value = match fetch_manifest(&ctx, &name) {
+            Ok(value) => value,
+            Err(e) => {
+                eprintln!("Failed to fetch /{}: {}", name, e);
+                manifest_report.errors.push(e.to_string());
+                failed = true;
+                continue;
             }
+        };
+        manifest_report.describe(&value);
+        if let Err(e) = check_artifact_urls(&ctx, &value) {
+            eprintln!("Refusing to sync /{}: {}", name, e);
+            manifest_report.errors.push(e.to_string());
+            failed = true;
+            continue;
         }
+        manifest_report.artifacts = targeted_artifacts(&ctx, &value).len();
+        manifests.push((index, channel, name, value));
+    }
 
-        if !perserve_dir {
-            println!(
-                "No useful file left in dir {}, removing the entire directory.",
-                date_dir.path().display()
+    if let Some(max_size) = args.max_size {
+        let mut sizes = HashMap::new();
+        for (_, _, _, value) in manifests.iter() {
+            for file in pending_downloads(&ctx, value) {
+                if sizes.contains_key(&file) {
+                    continue;
+                }
+                let size = upstreams
+                    .downloader
+                    .size(upstreams.ordered()[0], &file)
+                    .unwrap_or_else(|e| {
+                        println!("Failed to get size of /{}: {}", file, e);
+                        0
+                    });
+                sizes.insert(file, size);
+            }
+        }
+
+        let required: u64 = sizes.values().sum();
+        println!(
+            "{} bytes to download, at most {} bytes allowed",
+            required, max_size
+        );
+        let pruned = match max_size.checked_sub(required) {
+            Some(max_usage) => gc::prune(mirror_path, mirror_url, &retention, max_usage),
+            None => Err(anyhow!("Not enough space even for an empty mirror")),
+        };
+        if let Err(e) = pruned {
+            drop(lock);
+            abort_run(
+                report_path,
+                &mut report,
+                format!("Unable to stay under --max-size: {}", e),
+                1,
             );
-            remove_dir_all(date_dir.path()).unwrap();
+        }
+
+        ctx.quota = Some(Quota {
+            limit: max_size,
+            used: quota::dir_size(Path::new(mirror_path)),
+            sizes,
+        });
+    }
+
+    // Fetch rust components
+    for (index, channel, name, value) in manifests {
+        let manifest_report = &mut report.manifests[index];
+        let start = Instant::now();
+        let unfinished = value.clone();
+        let res = sync_manifest(&mut ctx, &name, value, manifest_report).and_then(|value| {
+            // Keep a dated copy of the channel manifests
+            if let Some(channel) = channel {
+                let date = value["date"].as_str().unwrap();
+                let alt_name = format!("dist/{}/channel-rust-{}.toml", date, channel);
+                publish(&ctx, &alt_name, &value)
+                    .map_err(|e| anyhow!("Failed to write /{}: {}", alt_name, e))?;
+            }
+            Ok(value)
+        });
+        manifest_report.duration = start.elapsed();
+        // The other channels are still synced
+        if let Err(e) = res {
+            eprintln!("Failed to sync /{}: {}", name, e);
+            manifest_report.errors.push(e.to_string());
+            retention.unfinished.push(unfinished);
+            failed = true;
         }
     }
+
+    let rustup_targets: Vec<&String> = if args.rustup_targets.is_empty() {
+        args.targets
+            .iter()
+            .filter(|target| RUSTUP_TARGETS.contains(&target.as_str()))
+            .collect()
+    } else {
+        args.rustup_targets.iter().collect()
+    };
+
+    let mut rustup_summary = RustupSummary::default();
+    if let Err(e) = sync_rustup(&ctx, &rustup_targets, &mut rustup_summary) {
+        eprintln!("Failed to sync rustup self update: {}", e);
+        report
+            .errors
+            .push(format!("Failed to sync rustup self update: {}", e));
+        failed = true;
+    }
+
+    // Fetch the installer script run by `curl | sh`
+    println!("Downloading rustup-init.sh...");
+    let res = fetch_rustup_file(&ctx, "rustup/rustup-init.sh", false);
+    // Export ships the upstream script, so that import rewrites it for its own url
+    if rustup_summary.record("rustup/rustup-init.sh".to_string(), res)
+        && let Err(e) = keep_upstream_installer(mirror_path, orig_path, args.rewrite_installer)
+            .and_then(|_| {
+                if args.rewrite_installer {
+                    rewrite_installer(mirror_path, mirror_url)?;
+                }
+                Ok(())
+            })
+    {
+        println!("Failed to rewrite rustup-init.sh: {}", e);
+    }
+    let _ = std::fs::remove_dir_all(Path::new(mirror_path).join(PARTIAL_DIR));
+    rustup_summary.print();
+    failed |= !rustup_summary.failed.is_empty();
+
+    report.gc_deleted = gc::collect(mirror_path, mirror_url, &retention);
+    for manifest_report in report.manifests.iter_mut() {
+        if let Some(channel) = &manifest_report.channel {
+            let file_name = format!("/channel-rust-{}.toml", channel);
+            manifest_report.gc_deleted_manifests = report
+                .gc_deleted
+                .iter()
+                .filter(|path| path.ends_with(&file_name))
+                .count();
+        }
+    }
+    let rustup_versions = gc::collect_rustup(mirror_path, &retention);
+    let served_version = served_rustup_version(mirror_path);
+    println!("Rustup versions served:");
+    for (version, targets) in rustup_versions.iter() {
+        let current = if Some(version) == served_version.as_ref() {
+            " (current)"
+        } else {
+            ""
+        };
+        println!("  {}{}: {}", version, current, targets.join(", "));
+    }
+    for variant in args.url_variant.iter() {
+        gc::collect_variant(mirror_path, &variant.root);
+    }
+
+    report.rustup = rustup_summary.to_json(served_version.as_ref(), &rustup_versions);
+    if let Err(e) = write_report(report_path, &report, !failed) {
+        eprintln!("{}", e);
+        failed = true;
+    }
+
+    drop(lock);
+    if failed {
+        std::process::exit(1);
+    }
 }
 
 pub fn normalize_path(path: &Path) -> PathBuf {
@@ -748,3 +1700,220 @@ pub fn normalize_path(path: &Path) -> PathBuf {
     }
     ret
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_path_resolves_dots() {
+        assert_eq!(
+            normalize_path(Path::new("/mirror/./dist/../dist/a.toml")),
+            PathBuf::from("/mirror/dist/a.toml")
+        );
+        assert_eq!(
+            normalize_path(Path::new("mirror/dist/2024-01-01/../../x")),
+            PathBuf::from("mirror/x")
+        );
+        assert_eq!(normalize_path(Path::new("/..")), PathBuf::from("/"));
+    }
+
+    #[test]
+    fn decode_path_decodes_all_escapes() {
+        assert_eq!(
+            decode_path("/dist/2024-01-01/a%20b%2Bc+d.tar.gz").unwrap(),
+            "dist/2024-01-01/a b+c+d.tar.gz"
+        );
+        assert_eq!(
+            artifact_path("https://static.rust-lang.org/dist/rust%2525.tar.gz").unwrap(),
+            "dist/rust%25.tar.gz"
+        );
+    }
+
+    #[test]
+    fn decode_path_rejects_escaping_paths() {
+        assert!(decode_path("/dist/../../etc/passwd").is_err());
+        assert!(decode_path("/dist/%2E%2E/%2E%2E/etc/passwd").is_err());
+        assert!(decode_path("/dist/..%2F..%2Fetc").is_err());
+        assert!(decode_path("/dist/..%5Cetc").is_err());
+        assert!(decode_path("/").is_err());
+        // Url normalizes literal dot segments away before we see them
+        assert_eq!(
+            artifact_path("https://example.com/dist/../../etc/passwd").unwrap(),
+            "etc/passwd"
+        );
+    }
+
+    #[test]
+    fn artifact_url_round_trips() {
+        let path = "dist/2024-01-01/a b+c%d#e.tar.gz";
+        let url = artifact_url("http://127.0.0.1:8000/", path);
+        assert_eq!(
+            url,
+            "http://127.0.0.1:8000/dist/2024-01-01/a%20b%2Bc%25d%23e.tar.gz"
+        );
+        assert_eq!(artifact_path(&url).unwrap(), path);
+    }
+
+    /// Write a rustup release to a fake upstream, with a corrupted archive binary for `corrupt`
+    fn fake_rustup_upstream(root: &Path, version: &str, targets: &[&str], corrupt: Option<&str>) {
+        let write = |path: String, data: &[u8]| {
+            let path = root.join(path);
+            create_dir_all(path.parent().unwrap()).unwrap();
+            File::create(&path).unwrap().write_all(data).unwrap();
+            let mut sha256_path = path.clone().into_os_string();
+            sha256_path.push(".sha256");
+            File::create(sha256_path)
+                .unwrap()
+                .write_all(format!("{}  rustup-init", file_sha256(&path).unwrap()).as_bytes())
+                .unwrap();
+        };
+        for target in targets {
+            let ext = if target.contains("windows") {
+                ".exe"
+            } else {
+                ""
+            };
+            let binary = format!("rustup-init {} {}", version, target);
+            write(
+                format!("rustup/dist/{}/rustup-init{}", target, ext),
+                binary.as_bytes(),
+            );
+            let archive = format!("rustup/archive/{}/{}/rustup-init{}", version, target, ext);
+            write(archive.clone(), binary.as_bytes());
+            if corrupt == Some(*target) {
+                File::create(root.join(archive))
+                    .unwrap()
+                    .write_all(b"corrupted")
+                    .unwrap();
+            }
+        }
+        File::create(root.join("rustup/release-stable.toml"))
+            .unwrap()
+            .write_all(format!("schema-version = \"1\"\nversion = \"{}\"\n", version).as_bytes())
+            .unwrap();
+    }
+
+    /// Sync rustup from a fake upstream into a fresh mirror, returning the test root
+    /// (with the mirror under `mirror/`) and whether the sync succeeded
+    fn sync_fake_rustup(name: &str, version: &str, corrupt: Option<&str>) -> (PathBuf, bool) {
+        let root = std::env::temp_dir().join(format!(
+            "rustup-mirror-test-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&root);
+        fake_rustup_upstream(&root.join("upstream"), version, &FAKE_TARGETS, corrupt);
+        let ok = resync_fake_rustup(&root);
+        (root, ok)
+    }
+
+    const FAKE_TARGETS: [&str; 2] = ["x86_64-unknown-linux-gnu", "x86_64-pc-windows-msvc"];
+
+    /// Sync rustup again from the fake upstream under `root`, telling whether it succeeded
+    fn resync_fake_rustup(root: &Path) -> bool {
+        let upstream_url = Url::from_directory_path(root.join("upstream"))
+            .unwrap()
+            .to_string();
+        let upstreams = Upstreams::new(
+            vec![upstream_url.clone()],
+            Downloader {
+                hardlink: false,
+                client: reqwest::blocking::Client::new(),
+                low_speed: None,
+                retries: 1,
+                retry_delay: Duration::ZERO,
+                rate: RateLimiter::new(None, Vec::new()),
+            },
+        );
+        let orig = root.join("orig").to_string_lossy().to_string();
+        let mirror = root.join("mirror").to_string_lossy().to_string();
+        let ctx = Context {
+            upstreams: &upstreams,
+            trusted_url: &upstream_url,
+            orig_path: &orig,
+            mirror_path: &mirror,
+            mirror_url: "http://127.0.0.1:8000",
+            filter_targets: HashSet::new(),
+            allowed_hosts: HashSet::new(),
+            quota: None,
+            keep_upstream_urls: false,
+            url_variants: &[],
+        };
+        let targets: Vec<String> = FAKE_TARGETS.iter().map(|t| t.to_string()).collect();
+        let targets: Vec<&String> = targets.iter().collect();
+        sync_rustup(&ctx, &targets, &mut RustupSummary::default()).is_ok()
+    }
+
+    #[test]
+    fn rustup_self_update_from_mirror() {
+        let (root, ok) = sync_fake_rustup("self-update", "1.27.1", None);
+        assert!(ok);
+
+        // What `rustup self update` does with RUSTUP_UPDATE_ROOT=<mirror>/rustup
+        let mirror = root.join("mirror/rustup");
+        let version = served_rustup_version(root.join("mirror").to_str().unwrap()).unwrap();
+        assert_eq!(version, "1.27.1");
+        for (target, ext) in [
+            ("x86_64-unknown-linux-gnu", ""),
+            ("x86_64-pc-windows-msvc", ".exe"),
+        ] {
+            let path = format!("archive/{}/{}/rustup-init{}", version, target, ext);
+            let binary = std::fs::read_to_string(mirror.join(&path)).unwrap();
+            assert_eq!(binary, format!("rustup-init {} {}", version, target));
+            assert!(mirror.join(format!("{}.sha256", path)).exists());
+        }
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn rustup_self_update_not_published_when_incomplete() {
+        let (root, ok) = sync_fake_rustup("incomplete", "1.27.1", Some("x86_64-unknown-linux-gnu"));
+        assert!(!ok);
+        assert!(served_rustup_version(root.join("mirror").to_str().unwrap()).is_none());
+        assert!(
+            !root
+                .join("mirror/rustup/archive/1.27.1/x86_64-unknown-linux-gnu/rustup-init")
+                .exists()
+        );
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn rustup_self_update_not_published_without_binaries() {
+        let (root, ok) = sync_fake_rustup("no-binaries", "1.27.1", None);
+        assert!(ok);
+
+        // Upstream announces a release whose binaries are neither in dist/ nor archive/
+        let upstream = root.join("upstream/rustup");
+        std::fs::remove_dir_all(upstream.join("dist")).unwrap();
+        File::create(upstream.join("release-stable.toml"))
+            .unwrap()
+            .write_all(b"schema-version = \"1\"\nversion = \"1.28.0\"\n")
+            .unwrap();
+        assert!(!resync_fake_rustup(&root));
+
+        let mirror = root.join("mirror");
+        assert_eq!(
+            served_rustup_version(mirror.to_str().unwrap()).as_deref(),
+            Some("1.27.1")
+        );
+        assert!(!mirror.join("rustup/archive/1.28.0").exists());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn self_update_manifest_schema() {
+        assert_eq!(
+            parse_self_update_manifest("schema-version = \"1\"\nversion = \"1.27.1\"").unwrap(),
+            "1.27.1"
+        );
+        assert!(
+            parse_self_update_manifest("schema-version = \"2\"\nversion = \"1.27.1\"").is_err()
+        );
+        assert!(parse_self_update_manifest("schema-version = \"1\"").is_err());
+        assert!(
+            parse_self_update_manifest("schema-version = \"1\"\nversion = \"../1.0.0\"").is_err()
+        );
+    }
+}
@@ -0,0 +1,76 @@
+use anyhow::{Error, anyhow};
+use std::collections::HashMap;
+use std::fs::{metadata, read_dir};
+use std::path::Path;
+
+/// Parse a size with an optional binary suffix, e.g. 200G or 512M
+pub fn parse_size(size: &str) -> Result<u64, Error> {
+    let size = size.trim();
+    let (number, shift) = match size.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => {
+            let shift = match c.to_ascii_uppercase() {
+                'K' => 10,
+                'M' => 20,
+                'G' => 30,
+                'T' => 40,
+                _ => return Err(anyhow!("Unknown size suffix in {}", size)),
+            };
+            (&size[..i], shift)
+        }
+        _ => (size, 0),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|e| anyhow!("Invalid size {}: {}", size, e))?;
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| anyhow!("Size {} too large", size))
+}
+
+/// Total size of all files under a directory
+pub fn dir_size(path: &Path) -> u64 {
+    let entries = match read_dir(path) {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+    let mut size = 0;
+    for entry in entries.flatten() {
+        match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => size += dir_size(&entry.path()),
+            Ok(_) => size += entry.metadata().map(|m| m.len()).unwrap_or(0),
+            Err(_) => {}
+        }
+    }
+    size
+}
+
+/// Disk space accounting of the mirror during a sync
+pub struct Quota {
+    pub limit: u64,
+    pub used: u64,
+    /// Estimated sizes of files yet to download
+    pub sizes: HashMap<String, u64>,
+}
+
+impl Quota {
+    /// Refuse to download a file that would exceed the quota
+    pub fn check(&self, path: &str) -> Result<(), Error> {
+        let size = self.sizes.get(path).copied().unwrap_or(0);
+        if self.used + size > self.limit {
+            return Err(anyhow!(
+                "Downloading /{} ({} bytes) would exceed the quota of {} bytes, {} bytes used",
+                path,
+                size,
+                self.limit,
+                self.used
+            ));
+        }
+        Ok(())
+    }
+
+    /// Account for a file just downloaded, replacing `replaced` bytes of an older copy
+    pub fn record(&mut self, file: &Path, replaced: u64) {
+        let size = metadata(file).map(|m| m.len()).unwrap_or(0);
+        self.used = (self.used + size).saturating_sub(replaced);
+    }
+}
@@ -0,0 +1,72 @@
+use chrono::{Local, NaiveTime};
+use std::sync::Mutex;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// A rate limit applying during part of the day
+pub struct Schedule {
+    pub from: NaiveTime,
+    /// Before `from` if the schedule spans midnight
+    pub to: NaiveTime,
+    /// Bytes per second, unlimited if None
+    pub limit: Option<u64>,
+}
+
+impl Schedule {
+    fn contains(&self, time: NaiveTime) -> bool {
+        if self.from <= self.to {
+            self.from <= time && time < self.to
+        } else {
+            self.from <= time || time < self.to
+        }
+    }
+}
+
+/// Token bucket shared by all downloads
+pub struct RateLimiter {
+    /// Bytes per second outside of the schedules, unlimited if None
+    pub limit: Option<u64>,
+    /// The first schedule matching the time of day wins
+    pub schedules: Vec<Schedule>,
+    /// Bytes which may be transferred now, and when it was last updated
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: Option<u64>, schedules: Vec<Schedule>) -> Self {
+        RateLimiter {
+            limit,
+            schedules,
+            bucket: Mutex::new((0.0, Instant::now())),
+        }
+    }
+
+    /// Limit in effect right now
+    pub fn current_limit(&self) -> Option<u64> {
+        let now = Local::now().time();
+        match self.schedules.iter().find(|s| s.contains(now)) {
+            Some(schedule) => schedule.limit,
+            None => self.limit,
+        }
+    }
+
+    /// Account for transferred bytes, sleeping as long as needed to stay under the limit
+    pub fn consume(&self, bytes: usize) {
+        let limit = match self.current_limit() {
+            Some(limit) => limit.max(1) as f64,
+            None => return,
+        };
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            // Allow bursts of at most one second worth of data
+            let tokens = (bucket.0 + now.duration_since(bucket.1).as_secs_f64() * limit).min(limit)
+                - bytes as f64;
+            *bucket = (tokens, now);
+            -tokens / limit
+        };
+        if wait > 0.0 {
+            sleep(Duration::from_secs_f64(wait));
+        }
+    }
+}
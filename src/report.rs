@@ -0,0 +1,119 @@
+use anyhow::Error;
+use chrono::{DateTime, Local};
+use serde_json::{Value, json};
+use std::fs::{File, rename};
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+
+/// What happened to one manifest during a run
+#[derive(Default)]
+pub struct ManifestReport {
+    pub name: String,
+    /// None for pinned toolchains
+    pub channel: Option<String>,
+    pub date: Option<String>,
+    pub version: Option<String>,
+    /// Artifacts of the manifest for the mirrored targets
+    pub artifacts: usize,
+    pub downloaded: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    /// Bytes written to the mirror, including failed attempts
+    pub bytes: u64,
+    pub duration: Duration,
+    /// Manifests of this channel deleted by garbage collection, not counting artifacts
+    pub gc_deleted_manifests: usize,
+    pub errors: Vec<String>,
+}
+
+impl ManifestReport {
+    pub fn new(name: &str, channel: Option<&str>) -> Self {
+        ManifestReport {
+            name: name.to_string(),
+            channel: channel.map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    /// Record the date and version of an upstream manifest
+    pub fn describe(&mut self, manifest: &toml::Value) {
+        self.date = manifest
+            .get("date")
+            .and_then(toml::Value::as_str)
+            .map(str::to_string);
+        self.version = ["rust", "rustc"].iter().find_map(|pkg| {
+            manifest
+                .get("pkg")?
+                .get(pkg)?
+                .get("version")?
+                .as_str()
+                .map(str::to_string)
+        });
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "name": self.name,
+            "channel": self.channel,
+            "date": self.date,
+            "version": self.version,
+            "artifacts": self.artifacts,
+            "downloaded": self.downloaded,
+            "skipped": self.skipped,
+            "failed": self.failed,
+            "bytes": self.bytes,
+            "duration": self.duration.as_secs_f64(),
+            "gc-deleted-manifests": self.gc_deleted_manifests,
+            "errors": self.errors,
+        })
+    }
+}
+
+/// Machine-readable summary of a run, written with --report
+pub struct Report {
+    pub started: DateTime<Local>,
+    pub manifests: Vec<ManifestReport>,
+    pub rustup: Value,
+    /// Files deleted by garbage collection, relative to the mirror root
+    pub gc_deleted: Vec<String>,
+    /// Failures not tied to a single manifest
+    pub errors: Vec<String>,
+}
+
+impl Report {
+    pub fn new() -> Self {
+        Report {
+            started: Local::now(),
+            manifests: Vec::new(),
+            rustup: Value::Null,
+            gc_deleted: Vec::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    fn to_json(&self, success: bool) -> Value {
+        let finished = Local::now();
+        json!({
+            "started": self.started.to_rfc3339(),
+            "finished": finished.to_rfc3339(),
+            "duration": (finished - self.started).to_std().unwrap_or_default().as_secs_f64(),
+            "success": success,
+            "manifests": self.manifests.iter().map(ManifestReport::to_json).collect::<Vec<_>>(),
+            "rustup": self.rustup,
+            "gc-deleted": self.gc_deleted,
+            "errors": self.errors,
+        })
+    }
+
+    /// Write the report through a temporary file, so that readers never see half of it
+    pub fn write(&self, path: &Path, success: bool) -> Result<(), Error> {
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let mut file = File::create(&tmp_path)?;
+        serde_json::to_writer_pretty(&mut file, &self.to_json(success))?;
+        file.write_all(b"\n")?;
+        rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,94 @@
+use crate::RELEASE_CHANNELS;
+use anyhow::{Error, anyhow};
+use chrono::NaiveDate;
+use std::fmt;
+use std::fs::read_to_string;
+use std::path::Path;
+use std::str::FromStr;
+
+/// A toolchain that can be pinned, e.g. nightly-2024-03-01 or 1.74.1
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Toolchain {
+    /// A release channel at a given date, e.g. nightly-2024-03-01
+    Dated { channel: String, date: NaiveDate },
+    /// A numbered release, e.g. 1.74.1
+    Version(String),
+}
+
+impl Toolchain {
+    /// Path of the manifest of this toolchain, relative to the mirror root
+    pub fn manifest_name(&self) -> String {
+        match self {
+            Toolchain::Dated { channel, date } => {
+                format!("dist/{}/channel-rust-{}.toml", date, channel)
+            }
+            Toolchain::Version(version) => format!("dist/channel-rust-{}.toml", version),
+        }
+    }
+
+    /// Date of this toolchain if it is a dated nightly
+    pub fn nightly_date(&self) -> Option<NaiveDate> {
+        match self {
+            Toolchain::Dated { channel, date } if channel == "nightly" => Some(*date),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Toolchain {
+    type Err = Error;
+
+    fn from_str(spec: &str) -> Result<Self, Error> {
+        for channel in RELEASE_CHANNELS {
+            if let Some(date) = spec
+                .strip_prefix(channel)
+                .and_then(|rest| rest.strip_prefix('-'))
+            {
+                let date = NaiveDate::parse_from_str(date, "%Y-%m-%d")
+                    .map_err(|e| anyhow!("Invalid date in toolchain {}: {}", spec, e))?;
+                return Ok(Toolchain::Dated {
+                    channel: channel.to_string(),
+                    date,
+                });
+            }
+        }
+
+        let parts: Vec<&str> = spec.split('.').collect();
+        if (2..=3).contains(&parts.len())
+            && parts
+                .iter()
+                .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit()))
+        {
+            return Ok(Toolchain::Version(spec.to_string()));
+        }
+
+        Err(anyhow!(
+            "Invalid toolchain {}, expected e.g. nightly-2024-03-01 or 1.74.1",
+            spec
+        ))
+    }
+}
+
+impl fmt::Display for Toolchain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Toolchain::Dated { channel, date } => write!(f, "{}-{}", channel, date),
+            Toolchain::Version(version) => write!(f, "{}", version),
+        }
+    }
+}
+
+/// Read a pin file, one toolchain per line, `#` starts a comment
+pub fn read_pin_file(path: &Path) -> Result<Vec<Toolchain>, Error> {
+    let data = read_to_string(path)
+        .map_err(|e| anyhow!("Unable to read pin file {}: {}", path.display(), e))?;
+    let mut pins = Vec::new();
+    for line in data.lines() {
+        let spec = line.split('#').next().unwrap().trim();
+        if spec.is_empty() {
+            continue;
+        }
+        pins.push(spec.parse()?);
+    }
+    Ok(pins)
+}
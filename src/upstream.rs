@@ -0,0 +1,56 @@
+use crate::download::Downloader;
+use anyhow::{Error, anyhow};
+use std::cell::RefCell;
+use std::path::PathBuf;
+
+/// Upstream servers tried in order of priority, failing ones are tried last
+pub struct Upstreams {
+    pub downloader: Downloader,
+    urls: Vec<String>,
+    /// Number of failures of each upstream during this run
+    failures: RefCell<Vec<u32>>,
+}
+
+impl Upstreams {
+    pub fn new(urls: Vec<String>, downloader: Downloader) -> Self {
+        let failures = RefCell::new(vec![0; urls.len()]);
+        Upstreams {
+            downloader,
+            urls,
+            failures,
+        }
+    }
+
+    /// Upstreams in the order to try: by number of failures, then by priority
+    pub fn ordered(&self) -> Vec<&str> {
+        let failures = self.failures.borrow();
+        let mut order: Vec<usize> = (0..self.urls.len()).collect();
+        order.sort_by_key(|&i| failures[i]);
+        order.into_iter().map(|i| self.urls[i].as_str()).collect()
+    }
+
+    /// Deprioritize an upstream for the rest of the run
+    pub fn report_failure(&self, url: &str) {
+        if let Some(i) = self.urls.iter().position(|u| u == url) {
+            self.failures.borrow_mut()[i] += 1;
+            println!("Upstream {} deprioritized", url);
+        }
+    }
+
+    /// Download a file from the first upstream that has it.
+    /// Returns the upstream used alongside the local path
+    pub fn download(&self, dir: &str, path: &str) -> Result<(PathBuf, &str), Error> {
+        let mut last_error = anyhow!("No upstream configured");
+        for upstream_url in self.ordered() {
+            match self.downloader.download(upstream_url, dir, path) {
+                Ok(file) => return Ok((file, upstream_url)),
+                Err(e) => {
+                    println!("Failed to fetch /{} from {}: {}", path, upstream_url, e);
+                    self.report_failure(upstream_url);
+                    last_error = e;
+                }
+            }
+        }
+        Err(last_error)
+    }
+}